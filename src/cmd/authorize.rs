@@ -0,0 +1,35 @@
+use crate::exoscale::exoscale::ExoscaleProvider;
+use anyhow::Result;
+use clap::Parser;
+
+#[derive(Parser)]
+#[clap(
+    name = "authorize",
+    about = "Authorize additional SSH keys on an instance"
+)]
+pub struct Authorize {
+    /// Public keys, or paths to files of public keys. Defaults to EXTRA_AUTHORIZED_KEYS
+    #[clap(value_name = "KEY")]
+    keys: Vec<String>,
+}
+
+impl Authorize {
+    pub async fn execute(&self) -> Result<()> {
+        let exoscale = ExoscaleProvider::new_provider(false);
+        match exoscale {
+            Ok(provider) => {
+                let keys = if self.keys.is_empty() {
+                    provider.options.extra_authorized_keys.clone()
+                } else {
+                    self.keys.clone()
+                };
+                let authorize = provider.authorize(&keys).await;
+                if let Err(err) = authorize {
+                    return Err(err.context("Error authorizing keys"));
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(())
+    }
+}
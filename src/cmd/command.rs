@@ -1,8 +1,9 @@
-use crate::exoscale::exoscale::ExoscaleProvider;
+use crate::error::ProviderError;
+use crate::exoscale::exoscale::{public_ip, ExoscaleProvider};
+use crate::options::options::from_env_or_error;
 use crate::ssh;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use std::env;
 
 #[derive(Parser)]
 #[clap(name = "command", about = "Command an instance")]
@@ -13,38 +14,26 @@ impl Command {
         let exoscale = ExoscaleProvider::new_provider(false);
         match exoscale {
             Ok(provider) => {
-                let command = env::var("COMMAND");
-                match command {
-                    Ok(..) => (),
-                    Err(err) => return Err(anyhow::anyhow!("Error getting COMMAND: {}", err)),
-                }
-                let private_key;
-                #[cfg(any(target_os = "linux", target_os = "macos"))]
-                {
-                    private_key = ssh::keys::get_private_key_raw_base(
-                        provider.options.machine_folder.clone(),
-                    );
-                }
-                #[cfg(target_os = "windows")]
-                {
-                    private_key = ssh::keys::get_private_key_filename(
-                        provider.options.machine_folder.clone(),
-                    );
-                }
-                let instance = provider.get_devpod_instance().await?;
+                let command = from_env_or_error("COMMAND")?;
+                let credentials = provider.ssh_credentials()?;
+                let instance = provider.require_devpod_instance().await?;
+                let public_ip = public_ip(&instance, &provider.options.machine_id)?;
 
                 let result = ssh::helper::execute_command(
-                    "devpod".to_string(),
-                    instance.public_ip.unwrap().clone(),
-                    private_key.clone(),
-                    command?,
+                    provider.options.ssh_user.clone(),
+                    public_ip,
+                    credentials,
+                    command,
+                    provider.ssh_options()?,
                 )
                 .await;
                 match result {
-                    Err(err) => return Err(anyhow::anyhow!("Error creating ssh client: {}", err)),
-                    _ => {
-                        println!("{}", result?);
+                    Err(err) => {
+                        return Err(ProviderError::from(err)).context("Error creating ssh client")
                     }
+                    // Hand the remote exit status back to DevPod
+                    Ok(0) => {}
+                    Ok(exit_code) => std::process::exit(exit_code),
                 }
             }
             Err(err) => return Err(err),
@@ -14,7 +14,7 @@ impl Create {
             Ok(provider) => {
                 let create = provider.create().await;
                 if let Err(err) = create {
-                    return Err(anyhow::anyhow!("Error creating instance: {}", err));
+                    return Err(err.context("Error creating instance"));
                 }
             }
             Err(err) => return Err(err),
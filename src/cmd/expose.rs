@@ -0,0 +1,28 @@
+use crate::exoscale::exoscale::ExoscaleProvider;
+use crate::exoscale::ingress::IngressRule;
+use anyhow::Result;
+use clap::Parser;
+
+#[derive(Parser)]
+#[clap(name = "expose", about = "Open additional ports on an instance")]
+pub struct Expose {
+    /// Rules like tcp:8080, tcp:3000-3010@192.0.2.0/24, udp:60000-61000 or icmp
+    #[clap(value_name = "RULE", required = true)]
+    rules: Vec<IngressRule>,
+}
+
+impl Expose {
+    pub async fn execute(&self) -> Result<()> {
+        let exoscale = ExoscaleProvider::new_provider(false);
+        match exoscale {
+            Ok(provider) => {
+                let expose = provider.expose(&self.rules).await;
+                if let Err(err) = expose {
+                    return Err(err.context("Error exposing ports"));
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(())
+    }
+}
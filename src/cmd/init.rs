@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
 
 use crate::exoscale::exoscale::ExoscaleProvider;
@@ -14,7 +14,7 @@ impl Init {
             Ok(provider) => provider.init().await,
             Err(err) => return Err(err),
         }
-        .expect("Error initializing account");
+        .context("Error initializing account")?;
         Ok(())
     }
 }
@@ -1,7 +1,13 @@
+pub mod authorize;
 pub(crate) mod command;
 pub mod create;
 pub mod delete;
+pub mod expose;
 pub mod init;
+pub mod rescue;
+pub mod revoke;
+pub mod rotate_key;
 pub mod start;
 pub mod status;
 pub mod stop;
+pub mod unexpose;
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use exoscale_rs::models::start_instance_request::RescueProfile;
+
+use crate::exoscale::exoscale::ExoscaleProvider;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Profile {
+    Netboot,
+    NetbootEfi,
+}
+
+impl From<Profile> for RescueProfile {
+    fn from(profile: Profile) -> Self {
+        match profile {
+            Profile::Netboot => RescueProfile::Netboot,
+            Profile::NetbootEfi => RescueProfile::NetbootEfi,
+        }
+    }
+}
+
+#[derive(Parser)]
+#[clap(name = "rescue", about = "Boot an instance into rescue mode")]
+pub struct Rescue {
+    /// Rescue environment to boot
+    #[clap(long, value_enum, default_value_t = Profile::NetbootEfi)]
+    profile: Profile,
+}
+
+impl Rescue {
+    pub async fn execute(&self) -> Result<()> {
+        let exoscale = ExoscaleProvider::new_provider(false);
+        match exoscale {
+            Ok(provider) => {
+                let rescue = provider.rescue(self.profile.into()).await;
+                if let Err(err) = rescue {
+                    return Err(err.context("Error booting instance into rescue mode"));
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+use crate::exoscale::exoscale::ExoscaleProvider;
+use anyhow::Result;
+use clap::Parser;
+
+#[derive(Parser)]
+#[clap(name = "revoke", about = "Revoke additional SSH keys from an instance")]
+pub struct Revoke {
+    /// Public keys, or paths to files of public keys
+    #[clap(value_name = "KEY", required = true)]
+    keys: Vec<String>,
+}
+
+impl Revoke {
+    pub async fn execute(&self) -> Result<()> {
+        let exoscale = ExoscaleProvider::new_provider(false);
+        match exoscale {
+            Ok(provider) => {
+                let revoke = provider.revoke(&self.keys).await;
+                if let Err(err) = revoke {
+                    return Err(err.context("Error revoking keys"));
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(())
+    }
+}
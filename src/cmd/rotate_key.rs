@@ -0,0 +1,26 @@
+use crate::exoscale::exoscale::ExoscaleProvider;
+use anyhow::Result;
+use clap::Parser;
+
+#[derive(Parser)]
+#[clap(
+    name = "rotate-key",
+    about = "Replace the SSH key of a running instance"
+)]
+pub struct RotateKey {}
+
+impl RotateKey {
+    pub async fn execute(&self) -> Result<()> {
+        let exoscale = ExoscaleProvider::new_provider(false);
+        match exoscale {
+            Ok(provider) => {
+                let rotate = provider.rotate_key().await;
+                if let Err(err) = rotate {
+                    return Err(err.context("Error rotating the instance key"));
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(())
+    }
+}
@@ -14,7 +14,7 @@ impl Start {
             Ok(provider) => {
                 let start = provider.start().await;
                 if let Err(err) = start {
-                    return Err(anyhow::anyhow!("Error starting instance: {}", err));
+                    return Err(err.context("Error starting instance"));
                 }
             }
             Err(err) => return Err(err),
@@ -13,7 +13,7 @@ impl Status {
             Ok(provider) => {
                 let status = provider.status().await;
                 if let Err(err) = status {
-                    return Err(anyhow::anyhow!("Error getting instance status: {}", err));
+                    return Err(err.context("Error getting instance status"));
                 }
                 println!("{}", status?);
             }
@@ -13,7 +13,7 @@ impl Stop {
             Ok(provider) => {
                 let stop = provider.stop().await;
                 if let Err(err) = stop {
-                    return Err(anyhow::anyhow!("Error stopping instance: {}", err));
+                    return Err(err.context("Error stopping instance"));
                 }
             }
             Err(err) => return Err(err),
@@ -0,0 +1,31 @@
+use crate::exoscale::exoscale::ExoscaleProvider;
+use crate::exoscale::ingress::IngressRule;
+use anyhow::Result;
+use clap::Parser;
+
+#[derive(Parser)]
+#[clap(
+    name = "unexpose",
+    about = "Close ports opened with expose on an instance"
+)]
+pub struct Unexpose {
+    /// Rules as given to expose, without a network they match any network
+    #[clap(value_name = "RULE", required = true)]
+    rules: Vec<IngressRule>,
+}
+
+impl Unexpose {
+    pub async fn execute(&self) -> Result<()> {
+        let exoscale = ExoscaleProvider::new_provider(false);
+        match exoscale {
+            Ok(provider) => {
+                let unexpose = provider.unexpose(&self.rules).await;
+                if let Err(err) = unexpose {
+                    return Err(err.context("Error unexposing ports"));
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(())
+    }
+}
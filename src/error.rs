@@ -0,0 +1,78 @@
+use crate::ssh::helper::SshError;
+use thiserror::Error;
+
+// Errors DevPod may run into, each with a stable exit code so callers can tell them apart
+#[derive(Debug, Error)]
+pub enum ProviderError {
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("Exoscale API error: {0}")]
+    Api(String),
+    #[error(transparent)]
+    Ssh(#[from] SshError),
+    #[error("Key store error: {0}")]
+    KeyStore(String),
+    #[error("State file error: {0}")]
+    StateFile(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    NotRunning(String),
+}
+
+impl<T> From<exoscale_rs::apis::Error<T>> for ProviderError {
+    fn from(err: exoscale_rs::apis::Error<T>) -> Self {
+        ProviderError::Api(err.to_string())
+    }
+}
+
+impl ProviderError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ProviderError::Config(_) => 3,
+            ProviderError::Api(_) => 4,
+            ProviderError::Ssh(_) => 5,
+            ProviderError::KeyStore(_) => 6,
+            ProviderError::NotFound(_) => 7,
+            ProviderError::StateFile(_) => 8,
+            ProviderError::NotRunning(_) => 9,
+        }
+    }
+
+    pub fn hint(&self) -> &'static str {
+        match self {
+            ProviderError::Config(_) => {
+                "Check the provider options with `devpod provider options exoscale`"
+            }
+            ProviderError::Api(_) => {
+                "Check that the API key is valid and allowed to manage compute resources in the zone"
+            }
+            ProviderError::Ssh(SshError::HostKeyMismatch(_)) => {
+                "Someone may be intercepting the connection, only reset the pinned key if the VM was rebuilt"
+            }
+            ProviderError::Ssh(_) => {
+                "Check that the workspace VM is running and reachable over SSH"
+            }
+            ProviderError::KeyStore(_) | ProviderError::StateFile(_) => {
+                "Check that the machine folder exists and is writable by the current user"
+            }
+            ProviderError::NotFound(_) => "Recreate the workspace with `devpod up --recreate`",
+            ProviderError::NotRunning(_) => "Start the workspace with `devpod up`",
+        }
+    }
+
+    // Prints the error chain with a hint and returns the process exit code to use
+    pub fn report(err: &anyhow::Error) -> i32 {
+        eprintln!("{:#}", err);
+        match err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<ProviderError>())
+        {
+            Some(provider_error) => {
+                eprintln!("Hint: {}", provider_error.hint());
+                provider_error.exit_code()
+            }
+            None => 1,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::error::ProviderError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// Parses a comma separated list of networks, see `parse_cidr`
+pub fn parse_cidrs(name: &str, value: &str) -> Result<Vec<String>, ProviderError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .map(|cidr| {
+            parse_cidr(cidr).map_err(|err| ProviderError::Config(format!("{}: {}", name, err)))
+        })
+        .collect()
+}
+
+// Parses an IPv4 or IPv6 network in CIDR notation, a bare address stands for that single host.
+// Host bits are cleared, so 192.0.2.10/24 becomes 192.0.2.0/24.
+pub fn parse_cidr(value: &str) -> Result<String, String> {
+    let (address, prefix) = match value.trim().split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (value.trim(), None),
+    };
+    let address: IpAddr = address
+        .parse()
+        .map_err(|_| format!("{:?} is not an IP address", address))?;
+
+    let max_prefix = match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let prefix: u32 = match prefix {
+        Some(prefix) => prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| {
+                format!(
+                    "{:?} is not a prefix length between 0 and {}",
+                    prefix, max_prefix
+                )
+            })?,
+        None => max_prefix,
+    };
+
+    let network = match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+        }
+    };
+    Ok(format!("{}/{}", network, prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_addresses_are_single_hosts() {
+        assert_eq!(parse_cidr("192.0.2.10").unwrap(), "192.0.2.10/32");
+        assert_eq!(parse_cidr("2001:db8::1").unwrap(), "2001:db8::1/128");
+    }
+
+    #[test]
+    fn clears_host_bits() {
+        assert_eq!(parse_cidr("192.0.2.10/24").unwrap(), "192.0.2.0/24");
+        assert_eq!(parse_cidr(" 10.1.2.3/8 ").unwrap(), "10.0.0.0/8");
+        assert_eq!(parse_cidr("2001:db8::1/32").unwrap(), "2001:db8::/32");
+    }
+
+    #[test]
+    fn accepts_prefix_zero() {
+        assert_eq!(parse_cidr("192.0.2.10/0").unwrap(), "0.0.0.0/0");
+        assert_eq!(parse_cidr("2001:db8::1/0").unwrap(), "::/0");
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for value in [
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/-1",
+            "192.0.2.0/",
+            "192.0.2",
+            "example.com",
+            "",
+        ] {
+            assert!(parse_cidr(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parses_lists() {
+        assert_eq!(
+            parse_cidrs("ALLOWED_SSH_CIDRS", "192.0.2.10/24, 2001:db8::1,").unwrap(),
+            vec!["192.0.2.0/24", "2001:db8::1/128"]
+        );
+        assert!(parse_cidrs("ALLOWED_SSH_CIDRS", "192.0.2.0/24,nope").is_err());
+    }
+}
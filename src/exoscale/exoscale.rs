@@ -1,19 +1,38 @@
-use crate::options::options::{from_env, Options};
-use crate::ssh::helper::map_str_to_size;
+use crate::error::ProviderError;
+use crate::exoscale::cidr::parse_cidr;
+use crate::exoscale::ingress::IngressRule;
+use crate::exoscale::instance_type::resolve_instance_type;
+use crate::exoscale::journal::{Journal, Resource};
+use crate::exoscale::machine::MachineState;
+use crate::exoscale::operation::{is_not_found, wait_for_operation, POLL_INTERVAL};
+use crate::exoscale::state::devpod_status;
+use crate::exoscale::template::resolve_template;
+use crate::options::options::{from_env, from_env_or_error, Options};
+use crate::ssh;
+use crate::ssh::authorized_keys;
+use crate::ssh::helper::{Credentials, SshError, SshOptions};
 use crate::ssh::keys;
+use crate::ssh::known_hosts;
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose, Engine as _};
 use exoscale_rs::apis::configuration::Configuration;
-use exoscale_rs::models::security_group_resource::Visibility;
-use exoscale_rs::models::start_instance_request::RescueProfile::NetbootEfi;
+use exoscale_rs::models::add_rule_to_security_group_request::{FlowDirection, Protocol};
+use exoscale_rs::models::start_instance_request::RescueProfile;
 use exoscale_rs::models::{
-    AddExternalSourceToSecurityGroupRequest, AddRuleToSecurityGroupRequest,
-    CreateSecurityGroupRequest, InstanceType, ListInstances200ResponseInstancesInner,
-    SecurityGroup, SecurityGroupResource, StartInstanceRequest, Template,
+    AddRuleToSecurityGroupRequest, CreateSecurityGroupRequest, Instance, InstanceState,
+    InstanceType, Operation, RegisterSshKeyRequest, SecurityGroup, SshKey, StartInstanceRequest,
+    Template,
 };
 use std::collections::HashMap;
-use std::env;
-use uuid::Uuid;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::{sleep, timeout, Instant};
+
+// Delay between two SSH login attempts while the instance boots
+const SSH_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// How long the source IP detection endpoint may take to answer
+const SOURCE_IP_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct ExoscaleProvider {
     configuration: Configuration,
@@ -22,34 +41,16 @@ pub struct ExoscaleProvider {
 
 impl ExoscaleProvider {
     pub fn new_provider(init: bool) -> Result<ExoscaleProvider> {
-        let api_key = env::var("EXOSCALE_API_KEY")
-            .context("Please set EXOSCALE_API_KEY environment variable");
-        let api_secret = env::var("EXOSCALE_API_SECRET")
-            .context("Please set EXOSCALE_API_SECRET environment variable");
-        let zone =
-            env::var("EXOSCALE_ZONE").context("Please set EXOSCALE_ZONE environment variable");
+        let api_key = from_env_or_error("EXOSCALE_API_KEY")?;
+        let api_secret = from_env_or_error("EXOSCALE_API_SECRET")?;
+        let zone = from_env_or_error("EXOSCALE_ZONE")?;
+        let options = from_env(init)?;
 
-        let mut configuration = Configuration::new(zone.as_ref().unwrap());
-        let options = from_env(init);
+        let mut configuration = Configuration::new(&zone);
+        configuration.api_key = api_key;
+        configuration.api_secret = api_secret;
+        configuration.zone = zone;
 
-        match api_key {
-            Ok(api_key) => {
-                configuration.api_key = api_key;
-            }
-            Err(err) => return Err(anyhow::anyhow!("Error getting API key: {}", err)),
-        }
-        match api_secret {
-            Ok(api_secret) => {
-                configuration.api_secret = api_secret;
-            }
-            Err(err) => return Err(anyhow::anyhow!("Error getting API secret: {}", err)),
-        }
-        match zone {
-            Ok(zone) => {
-                configuration.zone = zone;
-            }
-            Err(err) => return Err(anyhow::anyhow!("Error getting ZONE: {}", err)),
-        }
         let provider = ExoscaleProvider {
             configuration,
             options,
@@ -58,7 +59,48 @@ impl ExoscaleProvider {
         Ok(provider)
     }
 
-    pub async fn get_devpod_instance(&self) -> Result<Box<ListInstances200ResponseInstancesInner>> {
+    // Looks up the machine's instance, `None` means the workspace VM does not exist
+    pub async fn get_devpod_instance(&self) -> Result<Option<Box<Instance>>> {
+        if let Some(state) = MachineState::load(&self.options.machine_folder)? {
+            if state.zone == self.configuration.zone {
+                match exoscale_rs::apis::instance_api::get_instance(
+                    &self.configuration,
+                    &state.instance_id,
+                )
+                .await
+                {
+                    Ok(instance) => return Ok(Some(Box::new(instance))),
+                    // The state file is stale, fall back to the label scan
+                    Err(err) if is_not_found(&err) => {}
+                    Err(err) => {
+                        return Err(
+                            ProviderError::Api(format!("Error getting instance: {}", err)).into(),
+                        )
+                    }
+                }
+            }
+        }
+
+        let id = match self.find_devpod_instance_id().await? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let instance = match exoscale_rs::apis::instance_api::get_instance(&self.configuration, &id)
+            .await
+        {
+            Ok(instance) => instance,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => {
+                return Err(ProviderError::Api(format!("Error getting instance: {}", err)).into())
+            }
+        };
+        self.save_machine_state(&instance)?;
+
+        Ok(Some(Box::new(instance)))
+    }
+
+    // Scans the zone for the instance labelled with the machine id
+    async fn find_devpod_instance_id(&self) -> Result<Option<String>> {
         let instances = match exoscale_rs::apis::instance_api::list_instances(
             &self.configuration,
             None,
@@ -68,238 +110,1105 @@ impl ExoscaleProvider {
         .await
         {
             Ok(instances) => instances,
-            Err(err) => return Err(anyhow::anyhow!("Error getting instance list: {}", err)),
+            Err(err) => {
+                return Err(
+                    ProviderError::Api(format!("Error getting instance list: {}", err)).into(),
+                )
+            }
         };
 
         let instance_list = match instances.instances {
             Some(instance_list) => instance_list,
-            None => return Err(anyhow::anyhow!("No instance list found")),
+            None => return Ok(None),
         };
 
-        if instance_list.is_empty() {
-            return Err(anyhow::anyhow!("No instance found"));
-        }
-        let found_instance = instance_list
-            .iter()
-            .find(|instance| {
-                if let (Some(_labels), Some(devpod_instance), Some(devpod_instance_id)) = (
-                    &instance.labels,
-                    instance
-                        .labels
-                        .as_ref()
-                        .and_then(|l| l.get("devpod_instance")),
-                    instance
-                        .labels
-                        .as_ref()
-                        .and_then(|l| l.get("devpod_instance_id")),
-                ) {
-                    *devpod_instance == "true"
-                        && *devpod_instance_id == self.options.machine_id.clone()
-                } else {
-                    false
-                }
-            })
-            .cloned();
+        let found_instance = instance_list.iter().find(|instance| {
+            if let (Some(_labels), Some(devpod_instance), Some(devpod_instance_id)) = (
+                &instance.labels,
+                instance
+                    .labels
+                    .as_ref()
+                    .and_then(|l| l.get("devpod_instance")),
+                instance
+                    .labels
+                    .as_ref()
+                    .and_then(|l| l.get("devpod_instance_id")),
+            ) {
+                *devpod_instance == "true" && *devpod_instance_id == self.options.machine_id.clone()
+            } else {
+                false
+            }
+        });
 
-        if let Some(instance) = found_instance {
-            Ok(Box::new(instance))
-        } else {
-            Err(anyhow::anyhow!("No matching instance found"))
+        Ok(found_instance.and_then(|instance| instance.id.map(|id| id.to_string())))
+    }
+
+    fn save_machine_state(&self, instance: &Instance) -> Result<()> {
+        let instance_id = instance_id(instance)?;
+        let mut state = MachineState::new(instance_id, self.configuration.zone.clone());
+        state.security_group_id = instance
+            .security_groups
+            .as_ref()
+            .and_then(|security_groups| security_groups.first())
+            .and_then(|security_group| security_group.id)
+            .map(|id| id.to_string());
+        state.public_ip = instance.public_ip.clone();
+        // Best effort, status and delete must not fail or generate keys over the fingerprint
+        state.key_fingerprint = self
+            .existing_public_key()
+            .ok()
+            .flatten()
+            .and_then(|public_key| keys::public_key_fingerprint(&public_key));
+        state.save(&self.options.machine_folder)
+    }
+
+    // Like `get_devpod_instance`, for commands that need the workspace VM to exist
+    pub async fn require_devpod_instance(&self) -> Result<Box<Instance>> {
+        match self.get_devpod_instance().await? {
+            Some(instance) => Ok(instance),
+            None => Err(ProviderError::NotFound(format!(
+                "workspace VM {} does not exist",
+                self.options.machine_id
+            ))
+            .into()),
         }
     }
 
     pub async fn init(&self) -> Result<()> {
-        let _list = exoscale_rs::apis::zone_api::list_zones(&self.configuration).await?;
+        let _list = exoscale_rs::apis::zone_api::list_zones(&self.configuration)
+            .await
+            .map_err(ProviderError::from)?;
+        Ok(())
+    }
+
+    fn operation_timeout(&self) -> Duration {
+        Duration::from_secs(self.options.operation_timeout)
+    }
+
+    // The public key put on the VM: the user's own one if configured, the generated one otherwise
+    fn authorized_public_key(&self) -> Result<String> {
+        let public_key = match &self.options.ssh_public_key_path {
+            Some(public_key_path) => keys::read_user_public_key(public_key_path)?,
+            None => keys::get_public_key_base(
+                self.options.machine_folder.clone(),
+                self.options.ssh_key_type,
+                self.options.ssh_key_passphrase.as_deref(),
+            )?,
+        };
+        Ok(public_key)
+    }
+
+    // Like `authorized_public_key`, but `None` instead of generating a key pair
+    fn existing_public_key(&self) -> Result<Option<String>> {
+        match &self.options.ssh_public_key_path {
+            Some(public_key_path) => Ok(Some(keys::read_user_public_key(public_key_path)?)),
+            None => Ok(keys::find_public_key(
+                &self.options.machine_folder,
+                self.options.ssh_key_type,
+            )?),
+        }
+    }
+
+    pub fn ssh_credentials(&self) -> Result<Credentials> {
+        let passphrase = self.options.ssh_key_passphrase.as_deref();
+        let key = match &self.options.ssh_public_key_path {
+            Some(public_key_path) if self.options.ssh_use_agent => {
+                return Ok(Credentials::Agent(keys::read_user_public_key(
+                    public_key_path,
+                )?))
+            }
+            Some(public_key_path) => keys::read_user_private_key(public_key_path)?,
+            None => keys::get_private_key_raw_base(
+                self.options.machine_folder.clone(),
+                self.options.ssh_key_type,
+                passphrase,
+            )?,
+        };
+        keys::check_passphrase(&key, passphrase)?;
+        Ok(Credentials::PrivateKey {
+            key,
+            passphrase: passphrase.map(str::to_string),
+        })
+    }
+
+    pub fn ssh_options(&self) -> Result<SshOptions> {
+        Ok(SshOptions {
+            port: self.options.ssh_port,
+            connect_timeout: Duration::from_secs(self.options.ssh_connect_timeout),
+            connect_retries: self.options.ssh_connect_retries,
+            known_hosts: known_hosts::known_hosts_path(&self.options.machine_folder),
+            host_key: keys::get_host_public_key(&self.options.machine_folder)?,
+        })
+    }
+
+    async fn wait(&self, operation: Operation) -> Result<Operation> {
+        wait_for_operation(&self.configuration, operation, self.operation_timeout()).await
+    }
+
+    // Polls the instance until it reports the given state, or is gone when `target` is None
+    async fn wait_for_instance_state(&self, id: &str, target: Option<InstanceState>) -> Result<()> {
+        let timeout = self.operation_timeout();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let state = match exoscale_rs::apis::instance_api::get_instance(&self.configuration, id)
+                .await
+            {
+                Ok(instance) => instance.state,
+                Err(err) if is_not_found(&err) => {
+                    if target.is_none() {
+                        return Ok(());
+                    }
+                    return Err(ProviderError::Api(format!("Instance {} disappeared", id)).into());
+                }
+                Err(err) => {
+                    return Err(
+                        ProviderError::Api(format!("Error getting instance: {}", err)).into(),
+                    )
+                }
+            };
+            if target.is_some() && state == target {
+                return Ok(());
+            }
+            if state == Some(InstanceState::Error) {
+                return Err(
+                    ProviderError::Api(format!("Instance {} is in error state", id)).into(),
+                );
+            }
+            if Instant::now() >= deadline {
+                return Err(ProviderError::Api(format!(
+                    "Timed out after {}s waiting for instance {} to become {}",
+                    timeout.as_secs(),
+                    id,
+                    target.map_or("deleted".to_string(), |state| state.to_string())
+                ))
+                .into());
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    // Waits until the workspace can take commands: running with a public IP, accepting the
+    // SSH login and done with cloud-init. Progress goes to stderr, stdout belongs to DevPod.
+    async fn wait_until_ready(&self, id: &str) -> Result<()> {
+        eprintln!("Waiting for instance {} to get a public IP", id);
+        let public_ip = self.wait_for_public_ip(id).await?;
+
+        let credentials = self.ssh_credentials()?;
+
+        eprintln!("Waiting for SSH login on {}", public_ip);
+        self.wait_for_ssh(&public_ip, &credentials).await?;
+
+        eprintln!("Waiting for cloud-init to finish on {}", public_ip);
+        self.wait_for_cloud_init(&public_ip, &credentials).await?;
+
+        eprintln!("Instance {} is ready", id);
         Ok(())
     }
 
+    async fn wait_for_public_ip(&self, id: &str) -> Result<String> {
+        let timeout = Duration::from_secs(self.options.instance_ready_timeout);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let instance = exoscale_rs::apis::instance_api::get_instance(&self.configuration, id)
+                .await
+                .map_err(|err| ProviderError::Api(format!("Error getting instance: {}", err)))?;
+            if let (Some(InstanceState::Running), Some(public_ip)) =
+                (instance.state, instance.public_ip.clone())
+            {
+                self.save_machine_state(&instance)?;
+                return Ok(public_ip);
+            }
+            if Instant::now() >= deadline {
+                return Err(ProviderError::Api(format!(
+                    "Timed out after {}s waiting for instance {} to run with a public IP",
+                    timeout.as_secs(),
+                    id
+                ))
+                .into());
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn wait_for_ssh(&self, public_ip: &str, credentials: &Credentials) -> Result<()> {
+        let timeout = Duration::from_secs(self.options.ssh_ready_timeout);
+        let deadline = Instant::now() + timeout;
+        let mut attempt = 1;
+        loop {
+            let login = ssh::helper::check_login(
+                self.options.ssh_user.clone(),
+                public_ip.to_string(),
+                credentials.clone(),
+                self.ssh_options()?,
+            )
+            .await;
+            let err = match login {
+                Ok(()) => return Ok(()),
+                // Retrying won't make a different host key go away
+                Err(err @ SshError::HostKeyMismatch(_)) => {
+                    return Err(ProviderError::from(err).into())
+                }
+                Err(err) => err,
+            };
+            if Instant::now() >= deadline {
+                return Err(ProviderError::Ssh(SshError::Timeout(format!(
+                    "no SSH login on {} after {}s, last error: {}",
+                    public_ip,
+                    timeout.as_secs(),
+                    err
+                )))
+                .into());
+            }
+            eprintln!("SSH not ready yet (attempt {}): {}", attempt, err);
+            attempt += 1;
+            sleep(SSH_RETRY_DELAY).await;
+        }
+    }
+
+    async fn wait_for_cloud_init(&self, public_ip: &str, credentials: &Credentials) -> Result<()> {
+        let (exit_code, output) = ssh::helper::capture_command(
+            self.options.ssh_user.clone(),
+            public_ip.to_string(),
+            credentials.clone(),
+            "cloud-init status --wait".to_string(),
+            Duration::from_secs(self.options.cloud_init_timeout),
+            self.ssh_options()?,
+        )
+        .await
+        .map_err(ProviderError::from)?;
+
+        // Exit code 2 stands for "done with recoverable errors" in recent cloud-init releases
+        if output.contains("status: done") {
+            return Ok(());
+        }
+        if exit_code == 127 {
+            eprintln!("cloud-init is not installed on the template, not waiting for it");
+            return Ok(());
+        }
+        Err(ProviderError::Ssh(SshError::RemoteCommand(format!(
+            "cloud-init did not finish successfully (exit code {}): {}",
+            exit_code,
+            output.trim()
+        )))
+        .into())
+    }
+
+    // Runs a short maintenance command as the login user, failing unless it exits with 0
+    async fn run_remote(
+        &self,
+        public_ip: &str,
+        credentials: &Credentials,
+        command: String,
+    ) -> Result<()> {
+        let (exit_code, output) = ssh::helper::capture_command(
+            self.options.ssh_user.clone(),
+            public_ip.to_string(),
+            credentials.clone(),
+            command,
+            self.operation_timeout(),
+            self.ssh_options()?,
+        )
+        .await
+        .map_err(ProviderError::from)?;
+        if exit_code == 0 {
+            return Ok(());
+        }
+        Err(ProviderError::Ssh(SshError::RemoteCommand(format!(
+            "exit code {}: {}",
+            exit_code,
+            output.trim()
+        )))
+        .into())
+    }
+
     pub async fn delete(&self) -> Result<()> {
-        let devpod_instance = self.get_devpod_instance().await?;
-        let instance_id: Option<Uuid> = devpod_instance.id;
-        let sg_id: String = devpod_instance
+        // A create that did not finish leaves its resources in the journal
+        let mut journal = Journal::open(&self.options.machine_folder)?;
+        if !journal.is_empty() {
+            // An instance the journal missed would keep the security group in use
+            if let Some(devpod_instance) = self.get_devpod_instance().await? {
+                self.delete_instance_resources(&devpod_instance).await?;
+            }
+            self.rollback(&mut journal).await?;
+            return self.remove_machine_files();
+        }
+
+        let devpod_instance = self.require_devpod_instance().await?;
+        self.delete_instance_resources(&devpod_instance).await?;
+        self.remove_machine_files()
+    }
+
+    // Deletes the instance along with its security group and the key registered for it
+    async fn delete_instance_resources(&self, devpod_instance: &Instance) -> Result<()> {
+        let instance_id: String = instance_id(devpod_instance)?;
+        let sg_id: Option<String> = devpod_instance
             .security_groups
-            .unwrap()
-            .first()
-            .unwrap()
-            .id
-            .unwrap()
-            .to_string();
-
-        // Delete the instance
-        exoscale_rs::apis::instance_api::delete_instance(
-            &self.configuration,
-            &instance_id.as_ref().unwrap().to_string(),
-        )
-        .await?;
+            .as_ref()
+            .and_then(|security_groups| security_groups.first())
+            .and_then(|security_group| security_group.id)
+            .map(|id| id.to_string());
+
+        // Delete the instance, the security group stays in use until it is gone
+        self.delete_instance(&instance_id).await?;
 
-        // sleep(Duration::from_secs(10));
         //Delete security group
-        exoscale_rs::apis::security_group_api::delete_security_group(&self.configuration, &sg_id)
-            .await?;
+        if let Some(sg_id) = sg_id {
+            self.delete_security_group(&sg_id).await?;
+        }
 
+        // Only the key registered for this machine, others may be shared with other instances
+        for ssh_key in devpod_instance.ssh_keys.iter().flatten() {
+            if ssh_key.name.as_deref() == Some(self.options.machine_id.as_str()) {
+                self.delete_ssh_key(&self.options.machine_id).await?;
+            }
+        }
         Ok(())
     }
 
+    fn remove_machine_files(&self) -> Result<()> {
+        MachineState::remove(&self.options.machine_folder)?;
+        known_hosts::forget_host_keys(&self.options.machine_folder).map_err(ProviderError::from)?;
+        keys::remove_host_key_pair(&self.options.machine_folder)?;
+        Ok(())
+    }
+
+    // Deletes an instance and waits until it is gone, one that is gone already is fine
+    async fn delete_instance(&self, id: &str) -> Result<()> {
+        match exoscale_rs::apis::instance_api::delete_instance(&self.configuration, id).await {
+            Ok(operation) => {
+                self.wait(operation).await?;
+            }
+            Err(err) if is_not_found(&err) => {}
+            Err(err) => {
+                return Err(
+                    ProviderError::Api(format!("Error deleting instance {}: {}", id, err)).into(),
+                )
+            }
+        }
+        self.wait_for_instance_state(id, None).await
+    }
+
+    async fn delete_security_group(&self, id: &str) -> Result<()> {
+        match exoscale_rs::apis::security_group_api::delete_security_group(&self.configuration, id)
+            .await
+        {
+            Ok(operation) => {
+                self.wait(operation).await?;
+                Ok(())
+            }
+            Err(err) if is_not_found(&err) => Ok(()),
+            Err(err) => Err(ProviderError::Api(format!(
+                "Error deleting security group {}: {}",
+                id, err
+            ))
+            .into()),
+        }
+    }
+
+    // The id of the security group with the given name, if there is one
+    async fn find_security_group_id(&self, name: &str) -> Result<Option<String>> {
+        let security_groups =
+            exoscale_rs::apis::security_group_api::list_security_groups(&self.configuration, None)
+                .await
+                .map_err(|err| {
+                    ProviderError::Api(format!("Error listing security groups: {}", err))
+                })?;
+        Ok(security_groups
+            .security_groups
+            .unwrap_or_default()
+            .into_iter()
+            .find(|security_group| security_group.name.as_deref() == Some(name))
+            .and_then(|security_group| security_group.id)
+            .map(|id| id.to_string()))
+    }
+
     pub async fn start(&self) -> Result<()> {
-        let devpod_instance = self.get_devpod_instance().await?;
-        let id: Option<Uuid> = devpod_instance.id;
-        exoscale_rs::apis::instance_api::start_instance(
+        let devpod_instance = self.require_devpod_instance().await?;
+        let id: String = instance_id(&devpod_instance)?;
+        self.start_instance(&id, None).await?;
+        self.wait_until_ready(&id).await
+    }
+
+    // Reboots the instance into one of Exoscale's rescue environments instead of its disk
+    pub async fn rescue(&self, profile: RescueProfile) -> Result<()> {
+        let devpod_instance = self.require_devpod_instance().await?;
+        let id: String = instance_id(&devpod_instance)?;
+        if devpod_instance.state != Some(InstanceState::Stopped) {
+            self.stop_instance(&id).await?;
+        }
+        self.start_instance(&id, Some(profile)).await
+    }
+
+    async fn start_instance(&self, id: &str, rescue_profile: Option<RescueProfile>) -> Result<()> {
+        let operation = exoscale_rs::apis::instance_api::start_instance(
             &self.configuration,
-            &id.as_ref().unwrap().to_string(),
-            StartInstanceRequest {
-                rescue_profile: Option::from(NetbootEfi),
-            },
+            id,
+            StartInstanceRequest { rescue_profile },
         )
-        .await?;
+        .await
+        .map_err(ProviderError::from)?;
+        self.wait(operation).await?;
+        self.wait_for_instance_state(id, Some(InstanceState::Running))
+            .await?;
         Ok(())
     }
 
-    pub async fn stop(&self) -> Result<()> {
-        let devpod_instance = self.get_devpod_instance().await?;
-        let id: Option<Uuid> = devpod_instance.id;
-        exoscale_rs::apis::instance_api::stop_instance(
-            &self.configuration,
-            &id.as_ref().unwrap().to_string(),
+    // Replaces the generated workspace key of a running VM. The new key is authorized next to the
+    // old one and only takes over once a login with it worked, a failure restores the old key.
+    pub async fn rotate_key(&self) -> Result<()> {
+        if self.options.ssh_public_key_path.is_some() {
+            return Err(ProviderError::Config(
+                "rotate-key only rotates the generated key, SSH_PUBLIC_KEY_PATH points at your own"
+                    .to_string(),
+            )
+            .into());
+        }
+        let devpod_instance = self.require_devpod_instance().await?;
+        let public_ip = public_ip(&devpod_instance, &self.options.machine_id)?;
+
+        let old_credentials = self.ssh_credentials()?;
+        let old_public_key = self.authorized_public_key()?;
+        let passphrase = self.options.ssh_key_passphrase.as_deref();
+        let new_key_pair = keys::stage_key_pair(
+            &self.options.machine_folder,
+            self.options.ssh_key_type,
+            passphrase,
+        )?;
+        let new_credentials = Credentials::PrivateKey {
+            key: new_key_pair.private_key.clone(),
+            passphrase: passphrase.map(str::to_string),
+        };
+
+        eprintln!("Authorizing the new key on {}", public_ip);
+        if let Err(err) = self
+            .run_remote(
+                &public_ip,
+                &old_credentials,
+                authorized_keys::authorize_command(&new_key_pair.public_key),
+            )
+            .await
+        {
+            keys::discard_staged_key_pair(&self.options.machine_folder, self.options.ssh_key_type)?;
+            return Err(err.context("Error authorizing the new key"));
+        }
+
+        let switched = self
+            .switch_key(&public_ip, &new_credentials, &old_public_key)
+            .await;
+        if let Err(err) = switched {
+            eprintln!("Key rotation failed, restoring the old key");
+            if let Err(restore_err) = self
+                .restore_key(
+                    &public_ip,
+                    (&old_credentials, &old_public_key),
+                    (&new_credentials, &new_key_pair.public_key),
+                )
+                .await
+            {
+                eprintln!("Restoring the old key failed: {:#}", restore_err);
+            }
+            return Err(err);
+        }
+
+        // The old key would otherwise stay registered under the machine id
+        if self.options.register_ssh_key {
+            self.reregister_ssh_key(&new_key_pair.public_key)
+                .await
+                .context("Rotated the key on the VM, but registering the new key failed")?;
+        }
+
+        self.save_machine_state(&devpod_instance)?;
+        eprintln!(
+            "Rotated the workspace key, the new fingerprint is {}",
+            keys::public_key_fingerprint(&new_key_pair.public_key).unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    // Second half of a rotation: proves the new key works, revokes the old one and swaps the key
+    // files locally
+    async fn switch_key(
+        &self,
+        public_ip: &str,
+        new_credentials: &Credentials,
+        old_public_key: &str,
+    ) -> Result<()> {
+        eprintln!("Checking the login with the new key");
+        ssh::helper::check_login(
+            self.options.ssh_user.clone(),
+            public_ip.to_string(),
+            new_credentials.clone(),
+            self.ssh_options()?,
+        )
+        .await
+        .map_err(ProviderError::from)
+        .context("Error logging in with the new key")?;
+
+        eprintln!("Revoking the old key");
+        self.run_remote(
+            public_ip,
+            new_credentials,
+            authorized_keys::revoke_command(old_public_key),
+        )
+        .await
+        .context("Error revoking the old key")?;
+
+        keys::promote_staged_key_pair(&self.options.machine_folder, self.options.ssh_key_type)?;
+        Ok(())
+    }
+
+    // Puts the old key back in place of the new one, with whichever of the two still logs in
+    async fn restore_key(
+        &self,
+        public_ip: &str,
+        (old_credentials, old_public_key): (&Credentials, &str),
+        (new_credentials, new_public_key): (&Credentials, &str),
+    ) -> Result<()> {
+        // Only needed when the old key got revoked already, otherwise this is a no-op
+        if let Err(err) = self
+            .run_remote(
+                public_ip,
+                new_credentials,
+                authorized_keys::authorize_command(old_public_key),
+            )
+            .await
+        {
+            eprintln!("Error authorizing the old key again: {:#}", err);
+        }
+        self.run_remote(
+            public_ip,
+            old_credentials,
+            authorized_keys::revoke_command(new_public_key),
         )
         .await?;
+        keys::discard_staged_key_pair(&self.options.machine_folder, self.options.ssh_key_type)?;
+        Ok(())
+    }
+
+    // Adds public keys to the authorized_keys of the running VM
+    pub async fn authorize(&self, entries: &[String]) -> Result<()> {
+        let public_keys = self.extra_public_keys(entries)?;
+        let devpod_instance = self.require_devpod_instance().await?;
+        let public_ip = public_ip(&devpod_instance, &self.options.machine_id)?;
+
+        let command = public_keys
+            .iter()
+            .map(|public_key| authorized_keys::authorize_command(public_key))
+            .collect::<Vec<_>>()
+            .join(" && ");
+        self.run_remote(&public_ip, &self.ssh_credentials()?, command)
+            .await?;
+        for public_key in &public_keys {
+            eprintln!(
+                "Authorized {}",
+                keys::public_key_fingerprint(public_key).unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    // Removes public keys from the authorized_keys of the running VM
+    pub async fn revoke(&self, entries: &[String]) -> Result<()> {
+        let public_keys = self.extra_public_keys(entries)?;
+        let own_fingerprint = keys::public_key_fingerprint(&self.authorized_public_key()?);
+        if public_keys
+            .iter()
+            .any(|public_key| keys::public_key_fingerprint(public_key) == own_fingerprint)
+        {
+            return Err(ProviderError::Config(
+                "Refusing to revoke the key DevPod logs in with, use rotate-key to replace it"
+                    .to_string(),
+            )
+            .into());
+        }
+        let devpod_instance = self.require_devpod_instance().await?;
+        let public_ip = public_ip(&devpod_instance, &self.options.machine_id)?;
+
+        let command = public_keys
+            .iter()
+            .map(|public_key| authorized_keys::revoke_command(public_key))
+            .collect::<Vec<_>>()
+            .join(" && ");
+        self.run_remote(&public_ip, &self.ssh_credentials()?, command)
+            .await?;
+        for public_key in &public_keys {
+            eprintln!(
+                "Revoked {}",
+                keys::public_key_fingerprint(public_key).unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    // Opens ports on the running VM, like EXTRA_INGRESS_RULES does on create
+    pub async fn expose(&self, rules: &[IngressRule]) -> Result<()> {
+        let devpod_instance = self.require_devpod_instance().await?;
+        let sg_id = security_group_id(&devpod_instance, &self.options.machine_id)?;
+        let default_cidrs = if rules.iter().all(|rule| rule.cidr.is_some()) {
+            Vec::new()
+        } else {
+            self.ssh_source_cidrs().await?
+        };
+        self.add_ingress_rules(&sg_id, rules, &default_cidrs).await
+    }
+
+    // Closes ports on the running VM again. Rules without a network remove every matching rule.
+    pub async fn unexpose(&self, rules: &[IngressRule]) -> Result<()> {
+        if let Some(rule) = rules
+            .iter()
+            .find(|rule| rule.covers_port(self.options.ssh_port))
+        {
+            return Err(ProviderError::Config(format!(
+                "Refusing to remove {}, DevPod needs port {} to reach the VM",
+                rule, self.options.ssh_port
+            ))
+            .into());
+        }
+        let devpod_instance = self.require_devpod_instance().await?;
+        let sg_id = security_group_id(&devpod_instance, &self.options.machine_id)?;
+        let security_group =
+            exoscale_rs::apis::security_group_api::get_security_group(&self.configuration, &sg_id)
+                .await
+                .map_err(ProviderError::from)?;
+        let existing = security_group.rules.unwrap_or_default();
+
+        for rule in rules {
+            let matching: Vec<String> = existing
+                .iter()
+                .filter(|existing| rule.matches(existing))
+                .filter_map(|existing| existing.id.map(|id| id.to_string()))
+                .collect();
+            if matching.is_empty() {
+                eprintln!("No rule matches {}", rule);
+                continue;
+            }
+            for rule_id in matching {
+                let operation =
+                    exoscale_rs::apis::security_group_api::delete_rule_from_security_group(
+                        &self.configuration,
+                        &sg_id,
+                        &rule_id,
+                    )
+                    .await
+                    .map_err(ProviderError::from)?;
+                self.wait(operation).await?;
+            }
+            eprintln!("Removed {}", rule);
+        }
+        Ok(())
+    }
+
+    // Adds each rule for its own network, or for every default network if it has none
+    async fn add_ingress_rules(
+        &self,
+        sg_id: &str,
+        rules: &[IngressRule],
+        default_cidrs: &[String],
+    ) -> Result<()> {
+        for rule in rules {
+            let cidrs = match &rule.cidr {
+                Some(cidr) => std::slice::from_ref(cidr),
+                None => default_cidrs,
+            };
+            // An ICMP rule can't cover networks of the other address family
+            let cidrs: Vec<&String> = cidrs
+                .iter()
+                .filter(|cidr| rule.allows_network(cidr))
+                .collect();
+            if cidrs.is_empty() {
+                eprintln!(
+                    "Skipping {}, none of the SSH networks fits its address family",
+                    rule
+                );
+            }
+            for cidr in cidrs {
+                let operation = exoscale_rs::apis::security_group_api::add_rule_to_security_group(
+                    &self.configuration,
+                    sg_id,
+                    rule.request(cidr),
+                )
+                .await
+                .map_err(|err| {
+                    ProviderError::Api(format!(
+                        "Error adding the rule {} for {} to the security group: {}",
+                        rule, cidr, err
+                    ))
+                })?;
+                self.wait(operation).await?;
+                eprintln!("Allowed {} from {}", rule, cidr);
+            }
+        }
+        Ok(())
+    }
+
+    // Public keys for authorize and revoke. Registered Exoscale keys don't work here, the API
+    // never hands out their public half.
+    fn extra_public_keys(&self, entries: &[String]) -> Result<Vec<String>> {
+        let extra_keys = authorized_keys::resolve_extra_keys(entries)?;
+        if let Some(name) = extra_keys.registered.first() {
+            return Err(ProviderError::Config(format!(
+                "{} is neither a public key nor a path, registered Exoscale SSH keys can only be \
+                 attached when the instance is created",
+                name
+            ))
+            .into());
+        }
+        if extra_keys.public_keys.is_empty() {
+            return Err(ProviderError::Config("No public keys given".to_string()).into());
+        }
+        Ok(extra_keys.public_keys)
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        let devpod_instance = self.require_devpod_instance().await?;
+        let id: String = instance_id(&devpod_instance)?;
+        self.stop_instance(&id).await
+    }
+
+    async fn stop_instance(&self, id: &str) -> Result<()> {
+        let operation = exoscale_rs::apis::instance_api::stop_instance(&self.configuration, id)
+            .await
+            .map_err(ProviderError::from)?;
+        self.wait(operation).await?;
+        self.wait_for_instance_state(id, Some(InstanceState::Stopped))
+            .await?;
         Ok(())
     }
 
     pub async fn status(&self) -> Result<&str> {
-        let devpod_instance = self.get_devpod_instance().await?;
-
-        let status = match devpod_instance.state.unwrap().to_string().as_str() {
-            "running" => "Running",
-            "stopped" => "Stopped",
-            "notfound" => "NotFound",
-            "busy" => "Busy",
-            _ => "NotFound",
+        let devpod_instance = match self.get_devpod_instance().await? {
+            Some(instance) => instance,
+            None => return Ok("NotFound"),
+        };
+
+        let status = match devpod_instance.state {
+            Some(InstanceState::Error) => {
+                eprintln!(
+                    "Instance {} is in error state, check it in the Exoscale console or delete it",
+                    self.options.machine_id
+                );
+                devpod_status(InstanceState::Error)
+            }
+            Some(state) => devpod_status(state),
+            // The API did not report a state yet, the instance is still being set up
+            None => "Busy",
         };
 
         Ok(status)
     }
 
     pub async fn create(&self) -> Result<()> {
-        let public_key_base = keys::get_public_key_base(self.options.machine_folder.clone());
+        let mut journal = Journal::open(&self.options.machine_folder)?;
+        // A new VM comes with a new host key
+        known_hosts::forget_host_keys(&self.options.machine_folder).map_err(ProviderError::from)?;
+
+        let result = tokio::select! {
+            result = self.create_resources(&mut journal) => result,
+            _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("Creation interrupted")),
+        };
+
+        match result {
+            Ok(instance_id) => {
+                journal.clear()?;
+                self.wait_until_ready(&instance_id).await
+            }
+            Err(err) => {
+                if let Err(rollback_err) = self.rollback(&mut journal).await {
+                    eprintln!(
+                        "Rollback incomplete, run delete to finish the cleanup: {}",
+                        rollback_err
+                    );
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Tears down the journaled resources, newest first. Each resource leaves the journal once it
+    // is gone, so an interrupted rollback can be resumed later.
+    async fn rollback(&self, journal: &mut Journal) -> Result<()> {
+        while let Some(resource) = journal.last().cloned() {
+            match &resource {
+                Resource::Instance { id } => self.delete_instance(id).await?,
+                Resource::LabelledInstance => {
+                    if let Some(id) = self.find_devpod_instance_id().await? {
+                        self.delete_instance(&id).await?;
+                    }
+                }
+                Resource::SshKey { name } => self.delete_ssh_key(name).await?,
+                Resource::SecurityGroup { id } => self.delete_security_group(id).await?,
+                Resource::NamedSecurityGroup { name } => {
+                    if let Some(id) = self.find_security_group_id(name).await? {
+                        self.delete_security_group(&id).await?;
+                    }
+                }
+            }
+            journal.forget(&resource)?;
+        }
+        journal.clear()
+    }
+
+    // Networks allowed to reach SSH: the configured ones, otherwise the egress IP reported by the
+    // detection endpoint, otherwise anywhere
+    async fn ssh_source_cidrs(&self) -> Result<Vec<String>> {
+        if !self.options.allowed_ssh_cidrs.is_empty() {
+            return Ok(self.options.allowed_ssh_cidrs.clone());
+        }
+        if let Some(endpoint) = &self.options.auto_detect_source_ip {
+            let cidr = self.detect_source_ip(endpoint).await?;
+            eprintln!("Allowing SSH from the detected source address {}", cidr);
+            return Ok(vec![cidr]);
+        }
+        eprintln!("Allowing SSH from anywhere, set ALLOWED_SSH_CIDRS to restrict it");
+        Ok(vec!["0.0.0.0/0".to_string()])
+    }
+
+    // Asks an endpoint like https://api.ipify.org for the address our requests come from. It has
+    // to answer with the bare address.
+    async fn detect_source_ip(&self, endpoint: &str) -> Result<String> {
+        let request = async {
+            self.configuration
+                .client
+                .get(endpoint)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await
+        };
+        let body = match timeout(SOURCE_IP_TIMEOUT, request).await {
+            Ok(Ok(body)) => body,
+            Ok(Err(err)) => {
+                return Err(ProviderError::Config(format!(
+                    "Error detecting the source IP through {}: {}",
+                    endpoint, err
+                ))
+                .into())
+            }
+            Err(_) => {
+                return Err(ProviderError::Config(format!(
+                    "Timed out detecting the source IP through {}",
+                    endpoint
+                ))
+                .into())
+            }
+        };
+        let address: IpAddr = body.trim().parse().map_err(|_| {
+            ProviderError::Config(format!(
+                "{} did not answer with an IP address: {:?}",
+                endpoint,
+                body.trim()
+            ))
+        })?;
+        parse_cidr(&address.to_string()).map_err(|err| ProviderError::Config(err).into())
+    }
+
+    // Registers the public key under the machine id. A key left behind by an earlier create is
+    // replaced.
+    async fn register_ssh_key(&self, public_key: &str, journal: &mut Journal) -> Result<SshKey> {
+        let name = self.options.machine_id.clone();
+        self.delete_ssh_key(&name).await?;
 
+        journal.record(Resource::SshKey { name: name.clone() })?;
+        self.upload_ssh_key(&name, public_key).await?;
+        Ok(SshKey {
+            name: Some(name),
+            fingerprint: None,
+        })
+    }
+
+    // Swaps the key registered under the machine id for the rotated one
+    async fn reregister_ssh_key(&self, public_key: &str) -> Result<()> {
+        let name = self.options.machine_id.clone();
+        self.delete_ssh_key(&name).await?;
+        self.upload_ssh_key(&name, public_key).await
+    }
+
+    async fn upload_ssh_key(&self, name: &str, public_key: &str) -> Result<()> {
+        let operation = exoscale_rs::apis::ssh_key_api::register_ssh_key(
+            &self.configuration,
+            RegisterSshKeyRequest::new(name.to_string(), public_key.trim().to_string()),
+        )
+        .await
+        .map_err(|err| {
+            ProviderError::Api(format!("Error registering SSH key {}: {}", name, err))
+        })?;
+        self.wait(operation).await?;
+        Ok(())
+    }
+
+    // Makes sure a key named in EXTRA_AUTHORIZED_KEYS is registered
+    async fn check_ssh_key(&self, name: &str) -> Result<()> {
+        match exoscale_rs::apis::ssh_key_api::get_ssh_key(&self.configuration, name).await {
+            Ok(_) => Ok(()),
+            Err(err) if is_not_found(&err) => Err(ProviderError::Config(format!(
+                "EXTRA_AUTHORIZED_KEYS: {} is neither a public key nor a path, and no SSH key \
+                 with that name is registered",
+                name
+            ))
+            .into()),
+            Err(err) => {
+                Err(ProviderError::Api(format!("Error getting SSH key {}: {}", name, err)).into())
+            }
+        }
+    }
+
+    async fn delete_ssh_key(&self, name: &str) -> Result<()> {
+        match exoscale_rs::apis::ssh_key_api::delete_ssh_key(&self.configuration, name).await {
+            Ok(operation) => {
+                self.wait(operation).await?;
+                Ok(())
+            }
+            Err(err) if is_not_found(&err) => Ok(()),
+            Err(err) => {
+                Err(ProviderError::Api(format!("Error deleting SSH key {}: {}", name, err)).into())
+            }
+        }
+    }
+
+    // Creates the security group and instance, returning the id of the running instance
+    async fn create_resources(&self, journal: &mut Journal) -> Result<String> {
+        let public_key_base = self.authorized_public_key()?;
+        let (host_private_key, host_public_key) =
+            keys::make_host_key_pair(&self.options.machine_folder)?;
+
+        // Listing public templates and the ones registered by the organization
+        let mut template_list = Vec::new();
+        for visibility in ["public", "private"] {
+            let templates = exoscale_rs::apis::template_api::list_templates(
+                &self.configuration,
+                Some(visibility),
+                None,
+            )
+            .await;
+            if let Err(err) = templates {
+                return Err(
+                    ProviderError::Api(format!("Error getting template list: {}", err)).into(),
+                );
+            }
+            template_list.extend(templates?.templates.unwrap_or_default());
+        }
+        let template: Box<Template> = Box::new(resolve_template(
+            template_list,
+            &self.options.template,
+            &self.configuration.zone,
+        )?);
+
+        // Listing instance types
+        let instance_types =
+            exoscale_rs::apis::instance_type_api::list_instance_types(&self.configuration).await;
+        if let Err(err) = instance_types {
+            return Err(
+                ProviderError::Api(format!("Error getting instance type list: {}", err)).into(),
+            );
+        }
+        let instance_type_list = match instance_types?.instance_types {
+            Some(instance_type_list) => instance_type_list,
+            None => {
+                return Err(ProviderError::NotFound("No instance type found".to_string()).into())
+            }
+        };
+        let instance_type: Box<InstanceType> = Box::new(resolve_instance_type(
+            instance_type_list,
+            &self.options.instance_type,
+            &self.configuration.zone,
+        )?);
+
+        // Unknown key names would only fail once the instance gets created
+        let extra_keys = authorized_keys::resolve_extra_keys(&self.options.extra_authorized_keys)?;
+        for name in &extra_keys.registered {
+            self.check_ssh_key(name).await?;
+        }
+
+        let sg_name = self.options.machine_id.clone().to_string() + "-sg";
+        journal.record(Resource::NamedSecurityGroup {
+            name: sg_name.clone(),
+        })?;
         let sg_result = exoscale_rs::apis::security_group_api::create_security_group(
             &self.configuration,
             CreateSecurityGroupRequest {
-                name: self.options.machine_id.clone().to_string() + "-sg",
+                name: sg_name,
                 description: Some("Security group for devpod instance".to_string()),
             },
         )
         .await;
         if let Err(err) = sg_result {
-            return Err(anyhow::anyhow!("Error creating security group: {}", err));
+            return Err(
+                ProviderError::Api(format!("Error creating security group: {}", err)).into(),
+            );
         }
         let sg_result = sg_result?;
+        let sg_id = match sg_result
+            .reference
+            .as_ref()
+            .and_then(|reference| reference.id)
+        {
+            Some(id) => id,
+            None => {
+                return Err(ProviderError::Api(
+                    "Security group creation returned no id".to_string(),
+                )
+                .into())
+            }
+        };
+        self.wait(sg_result).await?;
 
-        let security_group_resource: Box<SecurityGroupResource> = Box::new(SecurityGroupResource {
-            id: sg_result.reference.as_ref().unwrap().id,
-            name: Some(self.options.machine_id.clone().to_string() + "-sg"),
-            visibility: Option::from(Visibility::Private),
-        });
-
-        let _s = exoscale_rs::apis::security_group_api::add_external_source_to_security_group(
-            &self.configuration,
-            &security_group_resource.id.unwrap().to_string(),
-            AddExternalSourceToSecurityGroupRequest {
-                cidr: "0.0.0.0/0".to_string(),
-            },
-        )
-        .await;
-        if let Err(err) = _s {
-            return Err(anyhow::anyhow!("Error creating security group: {}", err));
+        // One rule per network allowed to reach SSH
+        let ssh_cidrs = self.ssh_source_cidrs().await?;
+        for cidr in &ssh_cidrs {
+            let operation = exoscale_rs::apis::security_group_api::add_rule_to_security_group(
+                &self.configuration,
+                &sg_id.to_string(),
+                AddRuleToSecurityGroupRequest {
+                    description: Some(format!("SSH from {}", cidr)),
+                    start_port: Some(self.options.ssh_port.into()),
+                    end_port: Some(self.options.ssh_port.into()),
+                    flow_direction: FlowDirection::Ingress,
+                    icmp: None,
+                    network: Some(cidr.clone()),
+                    protocol: Protocol::Tcp,
+                    security_group: None,
+                },
+            )
+            .await
+            .map_err(|err| {
+                ProviderError::Api(format!(
+                    "Error adding the SSH rule for {} to the security group: {}",
+                    cidr, err
+                ))
+            })?;
+            self.wait(operation).await?;
         }
-
-        let _r = exoscale_rs::apis::security_group_api::add_rule_to_security_group(
-            &self.configuration,
-            &security_group_resource.id.unwrap().to_string(),
-            AddRuleToSecurityGroupRequest {
-                description: Some("desc".to_string()),
-                start_port: Some(22),
-                end_port: Some(22),
-                flow_direction:
-                    exoscale_rs::models::add_rule_to_security_group_request::FlowDirection::Ingress,
-                icmp: None,
-                network: None,
-                protocol: exoscale_rs::models::add_rule_to_security_group_request::Protocol::Tcp,
-                security_group: Some(security_group_resource),
-            },
+        self.add_ingress_rules(
+            &sg_id.to_string(),
+            &self.options.extra_ingress_rules,
+            &ssh_cidrs,
         )
-        .await;
-        if let Err(err) = _r {
-            return Err(anyhow::anyhow!(
-                "Error adding rule to a security group: {}",
-                err
-            ));
-        }
+        .await?;
 
         let security_group = exoscale_rs::apis::security_group_api::get_security_group(
             &self.configuration,
-            &sg_result
-                .reference
-                .as_ref()
-                .unwrap()
-                .id
-                .unwrap()
-                .to_string(),
+            &sg_id.to_string(),
         )
         .await;
         if let Err(err) = security_group {
-            return Err(anyhow::anyhow!("Error getting the security group: {}", err));
+            return Err(
+                ProviderError::Api(format!("Error getting the security group: {}", err)).into(),
+            );
         }
 
         let sg = security_group?.clone();
 
-        // Listing templates
-        let templates =
-            exoscale_rs::apis::template_api::list_templates(&self.configuration, None, None).await;
-        if let Err(err) = templates {
-            return Err(anyhow::anyhow!("Error getting template list: {}", err));
-        }
-        let template_list = templates?.templates;
-        if template_list.is_none() {
-            return Err(anyhow::anyhow!("No template found"));
-        }
-        let template: Box<Template> = Box::new(
-            template_list
-                .unwrap()
-                .iter()
-                .find_map(|template| {
-                    if template.name.as_ref().unwrap() == self.options.template.as_str() {
-                        Some(template.clone())
-                    } else {
-                        None
-                    }
-                })
-                .expect("REASON"),
-        );
-
-        // Listing instance types
-        let instance_types =
-            exoscale_rs::apis::instance_type_api::list_instance_types(&self.configuration).await;
-        if let Err(err) = instance_types {
-            return Err(anyhow::anyhow!("Error getting instance type list: {}", err));
+        // Keys attached through the registry reach the default user of templates that ignore the
+        // users section of the cloud-config
+        let mut ssh_keys = Vec::new();
+        if self.options.register_ssh_key {
+            ssh_keys.push(self.register_ssh_key(&public_key_base, journal).await?);
         }
-        let instance_type_list = instance_types?.instance_types;
-        if instance_type_list.is_none() {
-            return Err(anyhow::anyhow!("No instance type found"));
-        }
-        let instance_type: Box<InstanceType> = Box::new(
-            instance_type_list
-                .unwrap()
-                .iter()
-                .find_map(|instance_type| {
-                    if map_str_to_size(self.options.instance_type.as_str()) == instance_type.size {
-                        Some(instance_type.clone())
-                    } else {
-                        None
-                    }
-                })
-                .unwrap(),
-        );
+        ssh_keys.extend(extra_keys.registered.iter().map(|name| SshKey {
+            name: Some(name.clone()),
+            fingerprint: None,
+        }));
 
         // Creating labels
         let mut labels = HashMap::new();
@@ -309,16 +1218,43 @@ impl ExoscaleProvider {
             self.options.machine_id.clone(),
         );
 
+        // cloud-init hands registry keys to the default user only, so with any attached the login
+        // user becomes the default user instead of an extra one
+        let users = if ssh_keys.is_empty() {
+            "users:\n            -"
+        } else {
+            "users:\n            - default\n            user:"
+        };
         let user_data = general_purpose::STANDARD.encode(format!(
             r#"#cloud-config
-            users:
-            - name: devpod
+            {}
+              name: {}
               shell: /bin/bash
               groups: [ sudo, docker ]
               ssh_authorized_keys:
               - {}
-              sudo: [ "ALL=(ALL) NOPASSWD:ALL" ]"#,
-            public_key_base
+              sudo: [ "ALL=(ALL) NOPASSWD:ALL" ]
+            ssh_deletekeys: true
+            ssh_genkeytypes: []
+            ssh_keys:
+              ed25519_private: |
+                {}
+              ed25519_public: {}"#,
+            users,
+            serde_json::to_string(&self.options.ssh_user)?,
+            // Quoted, since key comments may contain characters YAML cares about
+            std::iter::once(&public_key_base)
+                .chain(&extra_keys.public_keys)
+                .map(|public_key| serde_json::to_string(public_key.trim()))
+                .collect::<Result<Vec<_>, _>>()?
+                .join("\n              - "),
+            // The block scalar needs every line of the key at the same indentation
+            host_private_key
+                .trim_end()
+                .lines()
+                .collect::<Vec<_>>()
+                .join("\n                "),
+            host_public_key
         ));
 
         // Constructing the request parameters for Instance creation
@@ -326,7 +1262,7 @@ impl ExoscaleProvider {
             anti_affinity_groups: None,
             instance_type,
             template,
-            disk_size: self.options.disk_size.parse()?,
+            disk_size: self.options.disk_size,
             labels: Some(labels),
             auto_start: Option::from(true),
             security_groups: Some(vec![SecurityGroup {
@@ -342,20 +1278,70 @@ impl ExoscaleProvider {
             name: Some(self.options.machine_id.clone().to_string()),
             ssh_key: None,
             ipv6_enabled: None,
-            ssh_keys: None,
+            ssh_keys: Some(ssh_keys).filter(|ssh_keys| !ssh_keys.is_empty()),
         };
 
         // let instance_create_params = CreateInstanceParams {
         //     create_instance_request: instance_request,
         // };
 
-        // Creating the instance
+        // Creating the instance, it carries the machine label from the start
+        journal.record(Resource::LabelledInstance)?;
         let instance =
             exoscale_rs::apis::instance_api::create_instance(&self.configuration, instance_request)
                 .await;
         if let Err(err) = instance {
-            return Err(anyhow::anyhow!("Error getting instance type list: {}", err));
+            return Err(ProviderError::Api(format!("Error creating instance: {}", err)).into());
         }
-        Ok(())
+        let operation = instance?;
+        let instance_id = match operation
+            .reference
+            .as_ref()
+            .and_then(|reference| reference.id)
+        {
+            Some(id) => id.to_string(),
+            None => {
+                return Err(ProviderError::Api(
+                    "Instance creation returned no instance id".to_string(),
+                )
+                .into())
+            }
+        };
+        self.wait(operation).await?;
+        self.wait_for_instance_state(&instance_id, Some(InstanceState::Running))
+            .await?;
+        Ok(instance_id)
     }
 }
+
+fn instance_id(instance: &Instance) -> Result<String> {
+    match instance.id {
+        Some(id) => Ok(id.to_string()),
+        None => Err(ProviderError::Api("Instance returned without an id".to_string()).into()),
+    }
+}
+
+// The address to reach the VM at, a stopped VM has none
+pub fn public_ip(instance: &Instance, machine_id: &str) -> Result<String> {
+    match &instance.public_ip {
+        Some(public_ip) => Ok(public_ip.clone()),
+        None => Err(ProviderError::NotRunning(format!(
+            "workspace VM {} has no public IP, is it running?",
+            machine_id
+        ))
+        .into()),
+    }
+}
+
+fn security_group_id(instance: &Instance, machine_id: &str) -> Result<String> {
+    instance
+        .security_groups
+        .as_ref()
+        .and_then(|security_groups| security_groups.first())
+        .and_then(|security_group| security_group.id)
+        .map(|id| id.to_string())
+        .ok_or_else(|| {
+            ProviderError::NotFound(format!("workspace VM {} has no security group", machine_id))
+                .into()
+        })
+}
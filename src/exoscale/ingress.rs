@@ -0,0 +1,266 @@
+use crate::error::ProviderError;
+use crate::exoscale::cidr::parse_cidr;
+use crate::exoscale::instance_type::api_name;
+use exoscale_rs::models::add_rule_to_security_group_request::{FlowDirection, Protocol};
+use exoscale_rs::models::{
+    AddRuleToSecurityGroupRequest, AddRuleToSecurityGroupRequestIcmp, SecurityGroupRule,
+};
+use std::fmt;
+use std::str::FromStr;
+
+// An additional ingress rule: `tcp:8080`, `tcp:3000-3010`, `udp:60000-61000`, `icmp` or `icmpv6`,
+// optionally followed by `@` and the network to allow, e.g. `tcp:8080@192.0.2.0/24`
+#[derive(Clone, Debug, PartialEq)]
+pub struct IngressRule {
+    pub protocol: Protocol,
+    pub ports: Option<(u16, u16)>,
+    pub cidr: Option<String>,
+}
+
+impl IngressRule {
+    pub fn request(&self, cidr: &str) -> AddRuleToSecurityGroupRequest {
+        // ICMP rules need a type, echo requests are what ping sends
+        let icmp = match self.protocol {
+            Protocol::Icmp => Some((8, 0)),
+            Protocol::Icmpv6 => Some((128, 0)),
+            _ => None,
+        };
+        AddRuleToSecurityGroupRequest {
+            description: Some(format!("{} from {}", self, cidr)),
+            start_port: self.ports.map(|(start, _)| start.into()),
+            end_port: self.ports.map(|(_, end)| end.into()),
+            flow_direction: FlowDirection::Ingress,
+            icmp: icmp.map(|(r#type, code)| {
+                Box::new(AddRuleToSecurityGroupRequestIcmp {
+                    code: Some(code),
+                    r#type: Some(r#type),
+                })
+            }),
+            network: Some(cidr.to_string()),
+            protocol: self.protocol,
+            security_group: None,
+        }
+    }
+
+    // Whether an existing rule is one this rule created. Without a network, any network matches.
+    pub fn matches(&self, rule: &SecurityGroupRule) -> bool {
+        let protocol = rule.protocol.as_ref().map(api_name);
+        let ports = match (rule.start_port, rule.end_port) {
+            (Some(start), Some(end)) => u16::try_from(start).ok().zip(u16::try_from(end).ok()),
+            _ => None,
+        };
+        let ingress = rule.flow_direction.as_ref().map(api_name);
+        ingress.as_deref() == Some("ingress")
+            && protocol.as_deref() == Some(api_name(&self.protocol).as_str())
+            && ports == self.ports
+            && self
+                .cidr
+                .as_ref()
+                .is_none_or(|cidr| rule.network.as_ref() == Some(cidr))
+    }
+
+    // ICMP rules only apply to IPv4 networks, ICMPv6 rules only to IPv6 networks
+    pub fn allows_network(&self, cidr: &str) -> bool {
+        let ipv6 = cidr.contains(':');
+        match self.protocol {
+            Protocol::Icmp => !ipv6,
+            Protocol::Icmpv6 => ipv6,
+            _ => true,
+        }
+    }
+
+    pub fn covers_port(&self, port: u16) -> bool {
+        matches!(self.protocol, Protocol::Tcp)
+            && self
+                .ports
+                .is_some_and(|(start, end)| (start..=end).contains(&port))
+    }
+}
+
+impl fmt::Display for IngressRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", api_name(&self.protocol))?;
+        match self.ports {
+            Some((start, end)) if start == end => write!(f, ":{}", start),
+            Some((start, end)) => write!(f, ":{}-{}", start, end),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for IngressRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (rule, cidr) = match value.trim().split_once('@') {
+            Some((rule, cidr)) => (rule, Some(parse_cidr(cidr)?)),
+            None => (value.trim(), None),
+        };
+        let (protocol, ports) = match rule.split_once(':') {
+            Some((protocol, ports)) => (protocol, Some(ports)),
+            None => (rule, None),
+        };
+
+        let protocol = match protocol.to_lowercase().as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            "icmp" => Protocol::Icmp,
+            "icmpv6" => Protocol::Icmpv6,
+            _ => {
+                return Err(format!(
+                    "unknown protocol {:?} in {:?}, expected tcp, udp, icmp or icmpv6",
+                    protocol, value
+                ))
+            }
+        };
+        let ports = match (protocol, ports) {
+            (Protocol::Tcp | Protocol::Udp, Some(ports)) => Some(parse_ports(ports)?),
+            (Protocol::Tcp | Protocol::Udp, None) => {
+                return Err(format!("{:?} needs a port or a port range", value))
+            }
+            (_, Some(_)) => return Err(format!("{:?} takes no ports", value)),
+            (_, None) => None,
+        };
+        let rule = IngressRule {
+            protocol,
+            ports,
+            cidr,
+        };
+        match &rule.cidr {
+            Some(cidr) if !rule.allows_network(cidr) => Err(format!(
+                "{:?} mixes {} with a network of the other address family",
+                value,
+                api_name(&rule.protocol)
+            )),
+            _ => Ok(rule),
+        }
+    }
+}
+
+// Parses a comma separated list of rules
+pub fn parse_ingress_rules(name: &str, value: &str) -> Result<Vec<IngressRule>, ProviderError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            rule.parse()
+                .map_err(|err| ProviderError::Config(format!("{}: {}", name, err)))
+        })
+        .collect()
+}
+
+fn parse_ports(ports: &str) -> Result<(u16, u16), String> {
+    let parse = |port: &str| match port.trim().parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!("{:?} is not a port between 1 and 65535", port)),
+    };
+    let (start, end) = match ports.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => (parse(ports)?, parse(ports)?),
+    };
+    if start > end {
+        return Err(format!("port range {:?} ends before it starts", ports));
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exoscale_rs::models::security_group_rule;
+
+    fn rule(value: &str) -> IngressRule {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let tcp = rule("tcp:8080");
+        assert_eq!(tcp.protocol, Protocol::Tcp);
+        assert_eq!(tcp.ports, Some((8080, 8080)));
+        assert_eq!(tcp.cidr, None);
+        assert_eq!(rule("tcp:3000-3010").ports, Some((3000, 3010)));
+        let udp = rule("udp:60000-61000");
+        assert_eq!(udp.protocol, Protocol::Udp);
+        assert_eq!(udp.ports, Some((60000, 61000)));
+        let icmp = rule("icmp");
+        assert_eq!(icmp.protocol, Protocol::Icmp);
+        assert_eq!(icmp.ports, None);
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert_eq!(
+            rule("tcp:8080@192.0.2.10/24").cidr.as_deref(),
+            Some("192.0.2.0/24")
+        );
+        assert_eq!(
+            rule("icmpv6@2001:db8::1").cidr.as_deref(),
+            Some("2001:db8::1/128")
+        );
+        assert!("tcp:8080@nowhere".parse::<IngressRule>().is_err());
+        assert!("icmp@2001:db8::/32".parse::<IngressRule>().is_err());
+        assert!("icmpv6@192.0.2.0/24".parse::<IngressRule>().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for value in [
+            "tcp:3010-3000",
+            "tcp:0",
+            "udp:70000",
+            "tcp",
+            "icmp:8",
+            "sctp:80",
+        ] {
+            assert!(value.parse::<IngressRule>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn displays_rules() {
+        assert_eq!(rule("TCP:8080@192.0.2.0/24").to_string(), "tcp:8080");
+        assert_eq!(rule("udp:60000-61000").to_string(), "udp:60000-61000");
+        assert_eq!(rule("icmp").to_string(), "icmp");
+    }
+
+    #[test]
+    fn parses_lists() {
+        let rules = parse_ingress_rules("EXTRA_INGRESS_RULES", "tcp:8080, icmp,").unwrap();
+        assert_eq!(rules, vec![rule("tcp:8080"), rule("icmp")]);
+        assert!(parse_ingress_rules("EXTRA_INGRESS_RULES", "tcp:8080,tcp").is_err());
+    }
+
+    #[test]
+    fn filters_networks_by_family() {
+        assert!(rule("icmp").allows_network("192.0.2.0/24"));
+        assert!(!rule("icmp").allows_network("2001:db8::/32"));
+        assert!(rule("icmpv6").allows_network("2001:db8::/32"));
+        assert!(!rule("icmpv6").allows_network("192.0.2.0/24"));
+        assert!(rule("tcp:22").allows_network("2001:db8::/32"));
+    }
+
+    #[test]
+    fn matches_security_group_rules() {
+        let existing = SecurityGroupRule {
+            protocol: Some(security_group_rule::Protocol::Tcp),
+            start_port: Some(3000),
+            end_port: Some(3010),
+            network: Some("192.0.2.0/24".to_string()),
+            flow_direction: Some(security_group_rule::FlowDirection::Ingress),
+            ..SecurityGroupRule::new()
+        };
+        assert!(rule("tcp:3000-3010").matches(&existing));
+        assert!(rule("tcp:3000-3010@192.0.2.0/24").matches(&existing));
+        assert!(!rule("tcp:3000-3010@198.51.100.0/24").matches(&existing));
+        assert!(!rule("tcp:3000").matches(&existing));
+        assert!(!rule("udp:3000-3010").matches(&existing));
+
+        let egress = SecurityGroupRule {
+            flow_direction: Some(security_group_rule::FlowDirection::Egress),
+            ..existing
+        };
+        assert!(!rule("tcp:3000-3010").matches(&egress));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::error::ProviderError;
+use exoscale_rs::models::instance_type::{Family, Size};
+use exoscale_rs::models::InstanceType;
+use serde::Serialize;
+
+// Parses INSTANCE_TYPE in the `family.size` notation of the Exoscale CLI, a bare size means standard
+pub fn parse_instance_type(instance_type: &str) -> Option<(Family, Size)> {
+    match instance_type.trim().split_once('.') {
+        Some((family, size)) => Some((map_str_to_family(family)?, map_str_to_size(size)?)),
+        None => Some((Family::Standard, map_str_to_size(instance_type.trim())?)),
+    }
+}
+
+// Picks the instance type matching INSTANCE_TYPE among those usable in the zone
+pub fn resolve_instance_type(
+    instance_types: Vec<InstanceType>,
+    instance_type: &str,
+    zone: &str,
+) -> Result<InstanceType, ProviderError> {
+    let available: Vec<InstanceType> = instance_types
+        .into_iter()
+        .filter(|candidate| candidate.authorized != Some(false))
+        .filter(|candidate| {
+            candidate
+                .zones
+                .as_ref()
+                .is_none_or(|zones| zones.iter().any(|name| api_name(name) == zone))
+        })
+        .collect();
+
+    if let Some((family, size)) = parse_instance_type(instance_type) {
+        if let Some(found) = available
+            .iter()
+            .find(|candidate| candidate.family == Some(family) && candidate.size == Some(size))
+        {
+            return Ok(found.clone());
+        }
+    }
+
+    let mut choices: Vec<String> = available
+        .iter()
+        .filter_map(|candidate| {
+            Some(format!(
+                "{}.{}",
+                api_name(&candidate.family?),
+                api_name(&candidate.size?)
+            ))
+        })
+        .collect();
+    choices.sort();
+    Err(ProviderError::Config(format!(
+        "Instance type {} is not available in {}, valid choices are: {}",
+        instance_type,
+        zone,
+        choices.join(", ")
+    )))
+}
+
+pub fn map_str_to_size(size_str: &str) -> Option<Size> {
+    match size_str {
+        "large" => Some(Size::Large),
+        "huge" => Some(Size::Huge),
+        "jumbo" => Some(Size::Jumbo),
+        "medium" => Some(Size::Medium),
+        "mega" => Some(Size::Mega),
+        "small" => Some(Size::Small),
+        "extra-large" => Some(Size::ExtraLarge),
+        "titan" => Some(Size::Titan),
+        "micro" => Some(Size::Micro),
+        "colossus" => Some(Size::Colossus),
+        "tiny" => Some(Size::Tiny),
+        _ => None,
+    }
+}
+
+pub fn map_str_to_family(family_str: &str) -> Option<Family> {
+    match family_str {
+        "standard" => Some(Family::Standard),
+        "cpu" => Some(Family::Cpu),
+        "memory" => Some(Family::Memory),
+        "storage" => Some(Family::Storage),
+        "colossus" => Some(Family::Colossus),
+        "gpu" => Some(Family::Gpu),
+        "gpu2" => Some(Family::Gpu2),
+        "gpu3" => Some(Family::Gpu3),
+        "gpu3080ti" => Some(Family::Gpu3080ti),
+        "gpua5000" => Some(Family::Gpua5000),
+        _ => None,
+    }
+}
+
+// The name the API uses for an enum value, e.g. `extra-large` for Size::ExtraLarge
+pub fn api_name<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exoscale_rs::models::ZoneName;
+
+    fn instance_type(
+        family: Family,
+        size: Size,
+        authorized: Option<bool>,
+        zones: Option<Vec<ZoneName>>,
+    ) -> InstanceType {
+        InstanceType {
+            family: Some(family),
+            size: Some(size),
+            authorized,
+            zones,
+            ..InstanceType::new()
+        }
+    }
+
+    #[test]
+    fn bare_size_means_standard() {
+        assert_eq!(
+            parse_instance_type("medium"),
+            Some((Family::Standard, Size::Medium))
+        );
+        assert_eq!(
+            parse_instance_type(" extra-large "),
+            Some((Family::Standard, Size::ExtraLarge))
+        );
+    }
+
+    #[test]
+    fn parses_family_and_size() {
+        assert_eq!(
+            parse_instance_type("cpu.large"),
+            Some((Family::Cpu, Size::Large))
+        );
+        assert_eq!(
+            parse_instance_type("gpu3080ti.small"),
+            Some((Family::Gpu3080ti, Size::Small))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_families_and_sizes() {
+        assert_eq!(parse_instance_type("quantum.large"), None);
+        assert_eq!(parse_instance_type("cpu.enormous"), None);
+        assert_eq!(parse_instance_type("enormous"), None);
+        assert_eq!(parse_instance_type("cpu."), None);
+    }
+
+    #[test]
+    fn resolves_within_the_zone() {
+        let instance_types = vec![
+            instance_type(Family::Standard, Size::Small, Some(true), None),
+            instance_type(
+                Family::Cpu,
+                Size::Large,
+                Some(true),
+                Some(vec![ZoneName::AtVie1]),
+            ),
+        ];
+        let found = resolve_instance_type(instance_types, "cpu.large", "at-vie-1").unwrap();
+        assert_eq!(found.family, Some(Family::Cpu));
+        assert_eq!(found.size, Some(Size::Large));
+    }
+
+    #[test]
+    fn lists_only_usable_choices() {
+        let instance_types = vec![
+            instance_type(Family::Standard, Size::Small, Some(true), None),
+            instance_type(Family::Standard, Size::Tiny, None, None),
+            instance_type(Family::Gpu, Size::Huge, Some(false), None),
+            instance_type(
+                Family::Memory,
+                Size::Huge,
+                Some(true),
+                Some(vec![ZoneName::ChGva2]),
+            ),
+        ];
+        let err = resolve_instance_type(instance_types, "memory.huge", "at-vie-1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Configuration error: Instance type memory.huge is not available in at-vie-1, valid \
+             choices are: standard.small, standard.tiny"
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use crate::exoscale::machine::{state_file_error, write_atomically};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+static CREATE_JOURNAL_FILE: &str = "create-journal.json";
+
+// A resource created on behalf of the machine that has to be removed again on rollback
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Resource {
+    // Written by earlier releases once the create call returned, still rolled back
+    SecurityGroup { id: String },
+    Instance { id: String },
+    SshKey { name: String },
+    // Recorded before the create call, which may be interrupted after Exoscale made the resource
+    // but before its id came back
+    NamedSecurityGroup { name: String },
+    LabelledInstance,
+}
+
+// Record of the resources made by an unfinished `create`, persisted in the machine folder
+pub struct Journal {
+    path: PathBuf,
+    resources: Vec<Resource>,
+}
+
+impl Journal {
+    pub fn open(machine_folder: &str) -> Result<Journal> {
+        let path = Path::new(machine_folder).join(CREATE_JOURNAL_FILE);
+        let resources = match fs::read_to_string(&path) {
+            Ok(content) => {
+                serde_json::from_str(&content).map_err(state_file_error("parsing", &path))?
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(state_file_error("reading", &path)(err).into()),
+        };
+        Ok(Journal { path, resources })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+
+    pub fn last(&self) -> Option<&Resource> {
+        self.resources.last()
+    }
+
+    pub fn record(&mut self, resource: Resource) -> Result<()> {
+        self.resources.push(resource);
+        self.save()
+    }
+
+    pub fn forget(&mut self, resource: &Resource) -> Result<()> {
+        self.resources.retain(|recorded| recorded != resource);
+        self.save()
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.resources.clear();
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(state_file_error("removing", &self.path)(err).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        write_atomically(&self.path, &serde_json::to_string_pretty(&self.resources)?)
+    }
+}
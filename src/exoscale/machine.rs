@@ -0,0 +1,83 @@
+use crate::error::ProviderError;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+static MACHINE_STATE_FILE: &str = "machine.json";
+
+// Bumped whenever the layout changes, older files are treated as stale
+const MACHINE_STATE_VERSION: u32 = 1;
+
+// Identity of the machine's Exoscale resources, written once `create` succeeded
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MachineState {
+    pub version: u32,
+    pub instance_id: String,
+    pub zone: String,
+    pub security_group_id: Option<String>,
+    pub public_ip: Option<String>,
+    pub key_fingerprint: Option<String>,
+}
+
+impl MachineState {
+    pub fn new(instance_id: String, zone: String) -> MachineState {
+        MachineState {
+            version: MACHINE_STATE_VERSION,
+            instance_id,
+            zone,
+            security_group_id: None,
+            public_ip: None,
+            key_fingerprint: None,
+        }
+    }
+
+    // Reads the state file, `None` when it is missing or written by an incompatible version
+    pub fn load(machine_folder: &str) -> Result<Option<MachineState>> {
+        let path = Path::new(machine_folder).join(MACHINE_STATE_FILE);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(state_file_error("reading", &path)(err).into()),
+        };
+        match serde_json::from_str::<MachineState>(&content) {
+            Ok(state) if state.version == MACHINE_STATE_VERSION => Ok(Some(state)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn save(&self, machine_folder: &str) -> Result<()> {
+        let path = Path::new(machine_folder).join(MACHINE_STATE_FILE);
+        write_atomically(&path, &serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn remove(machine_folder: &str) -> Result<()> {
+        let path = Path::new(machine_folder).join(MACHINE_STATE_FILE);
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(state_file_error("removing", &path)(err).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Writes to a temporary file first so a crash never leaves a truncated file behind
+pub fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(state_file_error("creating", parent))?;
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, contents).map_err(state_file_error("writing", Path::new(&temporary)))?;
+    fs::rename(&temporary, path).map_err(state_file_error("writing", path))?;
+    Ok(())
+}
+
+pub fn state_file_error<E: std::fmt::Display>(
+    action: &str,
+    path: &Path,
+) -> impl Fn(E) -> ProviderError {
+    let context = format!("{} {}", action, path.display());
+    move |err| ProviderError::StateFile(format!("Error {}: {}", context, err))
+}
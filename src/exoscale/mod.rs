@@ -1 +1,9 @@
+pub mod cidr;
 pub mod exoscale;
+pub mod ingress;
+pub mod instance_type;
+pub mod journal;
+pub mod machine;
+pub mod operation;
+pub mod state;
+pub mod template;
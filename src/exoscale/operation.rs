@@ -0,0 +1,80 @@
+use crate::error::ProviderError;
+use anyhow::Result;
+use exoscale_rs::apis::configuration::Configuration;
+use exoscale_rs::apis::Error;
+use exoscale_rs::models::operation::State;
+use exoscale_rs::models::Operation;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+// Delay between two polls of a pending operation or resource
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Follows an asynchronous operation until it leaves the pending state
+pub async fn wait_for_operation(
+    configuration: &Configuration,
+    operation: Operation,
+    timeout: Duration,
+) -> Result<Operation> {
+    let id = match operation.id {
+        Some(id) => id.to_string(),
+        None => {
+            return Err(ProviderError::Api("Operation returned without an id".to_string()).into())
+        }
+    };
+    let deadline = Instant::now() + timeout;
+    let mut operation = operation;
+
+    loop {
+        match operation.state {
+            Some(State::Success) => return Ok(operation),
+            Some(State::Failure) | Some(State::Timeout) => {
+                return Err(ProviderError::Api(format!(
+                    "Operation {} failed: {}",
+                    id,
+                    failure_message(&operation)
+                ))
+                .into())
+            }
+            Some(State::Pending) | None => {}
+        }
+
+        if Instant::now() >= deadline {
+            return Err(ProviderError::Api(format!(
+                "Timed out after {}s waiting for operation {}",
+                timeout.as_secs(),
+                id
+            ))
+            .into());
+        }
+        sleep(POLL_INTERVAL).await;
+
+        operation = match exoscale_rs::apis::operation_api::get_operation(configuration, &id).await
+        {
+            Ok(operation) => operation,
+            Err(err) => {
+                return Err(
+                    ProviderError::Api(format!("Error polling operation {}: {}", id, err)).into(),
+                )
+            }
+        };
+    }
+}
+
+// Whether an API call failed because the resource does not exist (anymore)
+pub fn is_not_found<T>(err: &Error<T>) -> bool {
+    matches!(err, Error::ResponseError(response) if response.status == 404)
+}
+
+fn failure_message(operation: &Operation) -> String {
+    let state = match operation.state {
+        Some(State::Timeout) => "timeout",
+        _ => "failure",
+    };
+    match (&operation.message, operation.reason) {
+        (Some(message), Some(reason)) => format!("{} ({:?}, {})", message, reason, state),
+        (Some(message), None) => format!("{} ({})", message, state),
+        (None, Some(reason)) => format!("{:?} ({})", reason, state),
+        (None, None) => state.to_string(),
+    }
+}
@@ -0,0 +1,53 @@
+use exoscale_rs::models::InstanceState;
+
+// Maps an Exoscale instance state onto the states DevPod understands. The match is exhaustive on
+// purpose, so a state added upstream fails to compile instead of being reported as missing.
+pub fn devpod_status(state: InstanceState) -> &'static str {
+    match state {
+        InstanceState::Running => "Running",
+        InstanceState::Stopped => "Stopped",
+        InstanceState::Starting
+        | InstanceState::Stopping
+        | InstanceState::Migrating
+        | InstanceState::Destroying
+        | InstanceState::Expunging => "Busy",
+        // Keep DevPod from acting on a broken VM until someone had a look at it
+        InstanceState::Error => "Busy",
+        InstanceState::Destroyed => "NotFound",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_every_instance_state() {
+        let table = [
+            (InstanceState::Running, "Running"),
+            (InstanceState::Stopped, "Stopped"),
+            (InstanceState::Starting, "Busy"),
+            (InstanceState::Stopping, "Busy"),
+            (InstanceState::Migrating, "Busy"),
+            (InstanceState::Destroying, "Busy"),
+            (InstanceState::Expunging, "Busy"),
+            (InstanceState::Error, "Busy"),
+            (InstanceState::Destroyed, "NotFound"),
+        ];
+        for (state, expected) in table {
+            // A state added upstream has to be added to the table as well
+            match state {
+                InstanceState::Running
+                | InstanceState::Stopped
+                | InstanceState::Starting
+                | InstanceState::Stopping
+                | InstanceState::Migrating
+                | InstanceState::Destroying
+                | InstanceState::Expunging
+                | InstanceState::Error
+                | InstanceState::Destroyed => {}
+            }
+            assert_eq!(devpod_status(state), expected, "state {}", state);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::error::ProviderError;
+use crate::exoscale::instance_type::api_name;
+use exoscale_rs::models::template::Visibility;
+use exoscale_rs::models::Template;
+use uuid::Uuid;
+
+// Picks the template TEMPLATE refers to: an id, an exact name, or a `family-version` alias such
+// as `ubuntu-22.04` that resolves to the newest matching template. Only templates usable in the
+// zone are considered.
+pub fn resolve_template(
+    templates: Vec<Template>,
+    template: &str,
+    zone: &str,
+) -> Result<Template, ProviderError> {
+    let template = template.trim();
+    let available: Vec<Template> = templates
+        .into_iter()
+        .filter(|candidate| {
+            candidate
+                .zones
+                .as_ref()
+                .is_none_or(|zones| zones.iter().any(|name| api_name(name) == zone))
+        })
+        .collect();
+
+    if let Ok(id) = Uuid::parse_str(template) {
+        return match available
+            .into_iter()
+            .find(|candidate| candidate.id == Some(id))
+        {
+            Some(found) => Ok(found),
+            None => Err(ProviderError::Config(format!(
+                "Template {} does not exist in {}",
+                id, zone
+            ))),
+        };
+    }
+
+    let named: Vec<&Template> = available
+        .iter()
+        .filter(|candidate| candidate.name.as_deref() == Some(template))
+        .collect();
+    match named.as_slice() {
+        [found] => return Ok((*found).clone()),
+        [] => {}
+        candidates => {
+            return Err(ProviderError::Config(format!(
+                "Template name {} is ambiguous, use one of these ids instead: {}",
+                template,
+                describe(candidates)
+            )))
+        }
+    }
+
+    if let Some((family, version)) = template.rsplit_once('-') {
+        let newest = available
+            .iter()
+            .filter(|candidate| matches_alias(candidate, family, version))
+            .max_by(|a, b| (&a.created_at, &a.build).cmp(&(&b.created_at, &b.build)));
+        if let Some(found) = newest {
+            return Ok(found.clone());
+        }
+    }
+
+    let family = template.split(['-', ' ']).next().unwrap_or(template);
+    let similar: Vec<&Template> = available
+        .iter()
+        .filter(|candidate| {
+            candidate
+                .family
+                .as_deref()
+                .is_some_and(|candidate_family| candidate_family.eq_ignore_ascii_case(family))
+        })
+        .collect();
+    let candidates = if similar.is_empty() {
+        available.iter().collect()
+    } else {
+        similar
+    };
+    Err(ProviderError::Config(format!(
+        "No template matches {} in {}, candidates are: {}",
+        template,
+        zone,
+        describe(&candidates)
+    )))
+}
+
+// `ubuntu-22.04` matches family ubuntu with version 22.04 (or 22.04.x), falling back to the name
+// for templates that carry no version
+fn matches_alias(candidate: &Template, family: &str, version: &str) -> bool {
+    let same_family = candidate
+        .family
+        .as_deref()
+        .is_some_and(|candidate_family| candidate_family.eq_ignore_ascii_case(family));
+    if !same_family {
+        return false;
+    }
+    match candidate.version.as_deref() {
+        Some(candidate_version) => {
+            candidate_version == version || candidate_version.starts_with(&format!("{}.", version))
+        }
+        None => candidate
+            .name
+            .as_deref()
+            .is_some_and(|name| name.split_whitespace().any(|word| word == version)),
+    }
+}
+
+fn describe(templates: &[&Template]) -> String {
+    let mut descriptions: Vec<String> = templates
+        .iter()
+        .map(|template| {
+            let visibility = match template.visibility {
+                Some(Visibility::Private) => "private",
+                _ => "public",
+            };
+            format!(
+                "{} ({}, {})",
+                template.name.as_deref().unwrap_or("unnamed"),
+                template.id.map(|id| id.to_string()).unwrap_or_default(),
+                visibility
+            )
+        })
+        .collect();
+    descriptions.sort();
+    descriptions.dedup();
+    descriptions.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exoscale_rs::models::ZoneName;
+
+    fn template(id: u128, name: &str, family: &str, version: &str, created_at: &str) -> Template {
+        Template {
+            id: Some(Uuid::from_u128(id)),
+            name: Some(name.to_string()),
+            family: Some(family.to_string()),
+            version: Some(version.to_string()).filter(|version| !version.is_empty()),
+            created_at: Some(created_at.to_string()),
+            visibility: Some(Visibility::Public),
+            zones: Some(vec![ZoneName::AtVie1]),
+            ..Template::new()
+        }
+    }
+
+    fn templates() -> Vec<Template> {
+        vec![
+            template(
+                1,
+                "Linux Ubuntu 22.04 LTS 64-bit",
+                "ubuntu",
+                "22.04",
+                "2023-01-01T00:00:00Z",
+            ),
+            template(
+                2,
+                "Linux Ubuntu 22.04 LTS 64-bit",
+                "ubuntu",
+                "22.04.3",
+                "2024-01-01T00:00:00Z",
+            ),
+            template(
+                3,
+                "Linux Debian 12 (Bookworm) 64-bit",
+                "debian",
+                "12",
+                "2024-01-01T00:00:00Z",
+            ),
+            template(
+                4,
+                "Ubuntu Legacy",
+                "ubuntu",
+                "22.040",
+                "2025-01-01T00:00:00Z",
+            ),
+            Template {
+                zones: Some(vec![ZoneName::ChGva2]),
+                ..template(5, "Linux Ubuntu 24.04 LTS 64-bit", "ubuntu", "24.04", "")
+            },
+            Template {
+                visibility: Some(Visibility::Private),
+                ..template(6, "team-golden-image", "custom", "", "2024-06-01T00:00:00Z")
+            },
+        ]
+    }
+
+    fn resolve(template: &str) -> Result<Template, ProviderError> {
+        resolve_template(templates(), template, "at-vie-1")
+    }
+
+    #[test]
+    fn resolves_ids() {
+        let found = resolve(&Uuid::from_u128(3).to_string()).unwrap();
+        assert_eq!(found.id, Some(Uuid::from_u128(3)));
+
+        // Exists, but not in the zone
+        let err = resolve(&Uuid::from_u128(5).to_string()).unwrap_err();
+        assert!(err.to_string().contains("does not exist in at-vie-1"));
+    }
+
+    #[test]
+    fn duplicate_names_are_ambiguous() {
+        let err = resolve("Linux Ubuntu 22.04 LTS 64-bit").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("is ambiguous"), "{}", message);
+        assert!(message.contains(&Uuid::from_u128(1).to_string()));
+        assert!(message.contains(&Uuid::from_u128(2).to_string()));
+    }
+
+    #[test]
+    fn alias_picks_the_newest() {
+        let found = resolve("ubuntu-22.04").unwrap();
+        assert_eq!(found.id, Some(Uuid::from_u128(2)));
+    }
+
+    #[test]
+    fn alias_matches_whole_version_components() {
+        let ubuntu = template(1, "Ubuntu", "ubuntu", "22.04.1", "");
+        assert!(matches_alias(&ubuntu, "ubuntu", "22.04"));
+        assert!(matches_alias(&ubuntu, "Ubuntu", "22.04.1"));
+        let legacy = template(4, "Ubuntu Legacy", "ubuntu", "22.040", "");
+        assert!(!matches_alias(&legacy, "ubuntu", "22.04"));
+        assert!(!matches_alias(&ubuntu, "debian", "22.04"));
+        let unversioned = template(7, "Linux Arch Rolling", "arch", "", "");
+        assert!(matches_alias(&unversioned, "arch", "Rolling"));
+    }
+
+    #[test]
+    fn finds_private_templates() {
+        let found = resolve("team-golden-image").unwrap();
+        assert_eq!(found.id, Some(Uuid::from_u128(6)));
+    }
+
+    #[test]
+    fn lists_candidates_when_nothing_matches() {
+        let message = resolve("ubuntu-20.04").unwrap_err().to_string();
+        assert!(message.starts_with("Configuration error: No template matches ubuntu-20.04"));
+        assert!(message.contains(&format!(
+            "Linux Ubuntu 22.04 LTS 64-bit ({}, public)",
+            Uuid::from_u128(2)
+        )));
+        assert!(message.contains("Ubuntu Legacy"));
+        // Only similar templates of the zone are listed
+        assert!(!message.contains("Debian"));
+        assert!(!message.contains("24.04"));
+
+        let message = resolve("nonsense").unwrap_err().to_string();
+        assert!(message.contains(&format!(
+            "team-golden-image ({}, private)",
+            Uuid::from_u128(6)
+        )));
+    }
+}
@@ -1,4 +1,5 @@
 mod cmd;
+mod error;
 mod exoscale;
 mod options;
 mod ssh;
@@ -6,13 +7,20 @@ mod ssh;
 use anyhow::Result;
 use clap::Parser;
 
+use crate::cmd::authorize::Authorize;
 use crate::cmd::command::Command;
 use crate::cmd::create::Create;
 use crate::cmd::delete::Delete;
+use crate::cmd::expose::Expose;
 use crate::cmd::init::Init;
+use crate::cmd::rescue::Rescue;
+use crate::cmd::revoke::Revoke;
+use crate::cmd::rotate_key::RotateKey;
 use crate::cmd::start::Start;
 use crate::cmd::status::Status;
 use crate::cmd::stop::Stop;
+use crate::cmd::unexpose::Unexpose;
+use crate::error::ProviderError;
 
 #[derive(Parser)]
 enum DevPodProviderExoscale {
@@ -23,6 +31,12 @@ enum DevPodProviderExoscale {
     Start(Start),
     Stop(Stop),
     Status(Status),
+    Rescue(Rescue),
+    RotateKey(RotateKey),
+    Authorize(Authorize),
+    Revoke(Revoke),
+    Expose(Expose),
+    Unexpose(Unexpose),
 }
 
 impl DevPodProviderExoscale {
@@ -35,6 +49,12 @@ impl DevPodProviderExoscale {
             Self::Start(options) => options.execute().await,
             Self::Stop(options) => options.execute().await,
             Self::Status(options) => options.execute().await,
+            Self::Rescue(options) => options.execute().await,
+            Self::RotateKey(options) => options.execute().await,
+            Self::Authorize(options) => options.execute().await,
+            Self::Revoke(options) => options.execute().await,
+            Self::Expose(options) => options.execute().await,
+            Self::Unexpose(options) => options.execute().await,
         }
     }
 }
@@ -43,7 +63,6 @@ impl DevPodProviderExoscale {
 async fn main() {
     let command = DevPodProviderExoscale::parse();
     if let Err(err) = command.execute().await {
-        eprintln!("{}", err);
-        std::process::exit(1);
+        std::process::exit(ProviderError::report(&err));
     }
 }
@@ -1,45 +1,278 @@
+use crate::error::ProviderError;
+use crate::exoscale::cidr::parse_cidrs;
+use crate::exoscale::ingress::{parse_ingress_rules, IngressRule};
+use crate::ssh::authorized_keys::split_extra_keys;
+use crate::ssh::keys::KeyType;
 use std::env;
+use std::fs;
 
 #[derive(Default)]
 pub struct Options {
     pub template: String,
     pub instance_type: String,
-    pub disk_size: String,
+    pub disk_size: i64,
     pub machine_id: String,
     pub machine_folder: String,
+    pub operation_timeout: u64,
+    pub instance_ready_timeout: u64,
+    pub ssh_ready_timeout: u64,
+    pub cloud_init_timeout: u64,
+    pub ssh_port: u16,
+    pub ssh_connect_timeout: u64,
+    pub ssh_connect_retries: u32,
+    pub ssh_key_type: KeyType,
+    pub ssh_public_key_path: Option<String>,
+    pub ssh_use_agent: bool,
+    pub ssh_key_passphrase: Option<String>,
+    pub register_ssh_key: bool,
+    pub extra_authorized_keys: Vec<String>,
+    pub ssh_user: String,
+    pub allowed_ssh_cidrs: Vec<String>,
+    pub auto_detect_source_ip: Option<String>,
+    pub extra_ingress_rules: Vec<IngressRule>,
 }
 
-pub fn from_env(init: bool) -> Options {
-    let template = from_env_or_error("TEMPLATE");
+pub fn from_env(init: bool) -> Result<Options, ProviderError> {
+    let template = from_env_or_error("TEMPLATE")?;
 
-    let instance_type = from_env_or_error("INSTANCE_TYPE");
+    let instance_type = from_env_or_error("INSTANCE_TYPE")?;
 
-    let disk_size = from_env_or_error("DISK_SIZE");
+    let disk_size = parse("DISK_SIZE", &from_env_or_error("DISK_SIZE")?)?;
+
+    let operation_timeout = parse(
+        "OPERATION_TIMEOUT",
+        &from_env_or_default("OPERATION_TIMEOUT", "300"),
+    )?;
+
+    let instance_ready_timeout = parse(
+        "INSTANCE_READY_TIMEOUT",
+        &from_env_or_default("INSTANCE_READY_TIMEOUT", "300"),
+    )?;
+
+    let ssh_ready_timeout = parse(
+        "SSH_READY_TIMEOUT",
+        &from_env_or_default("SSH_READY_TIMEOUT", "300"),
+    )?;
+
+    let cloud_init_timeout = parse(
+        "CLOUD_INIT_TIMEOUT",
+        &from_env_or_default("CLOUD_INIT_TIMEOUT", "900"),
+    )?;
+
+    let ssh_port = parse("SSH_PORT", &from_env_or_default("SSH_PORT", "22"))?;
+
+    let ssh_connect_timeout = parse(
+        "SSH_CONNECT_TIMEOUT",
+        &from_env_or_default("SSH_CONNECT_TIMEOUT", "10"),
+    )?;
+
+    let ssh_connect_retries = parse(
+        "SSH_CONNECT_RETRIES",
+        &from_env_or_default("SSH_CONNECT_RETRIES", "8"),
+    )?;
+
+    let ssh_key_type = from_env_or_default("SSH_KEY_TYPE", "ed25519")
+        .parse()
+        .map_err(|err| ProviderError::Config(format!("SSH_KEY_TYPE: {}", err)))?;
+
+    let ssh_public_key_path =
+        Some(from_env_or_default("SSH_PUBLIC_KEY_PATH", "")).filter(|path| !path.trim().is_empty());
+
+    let ssh_use_agent = parse_flag(
+        "SSH_USE_AGENT",
+        &from_env_or_default("SSH_USE_AGENT", "false"),
+    )?;
+    if ssh_use_agent && ssh_public_key_path.is_none() {
+        return Err(ProviderError::Config(
+            "SSH_USE_AGENT requires SSH_PUBLIC_KEY_PATH to select the agent key to authorize"
+                .to_string(),
+        ));
+    }
+
+    let ssh_key_passphrase = key_passphrase()?;
+
+    let register_ssh_key = parse_flag(
+        "REGISTER_SSH_KEY",
+        &from_env_or_default("REGISTER_SSH_KEY", "false"),
+    )?;
+
+    let extra_authorized_keys = split_extra_keys(&from_env_or_default("EXTRA_AUTHORIZED_KEYS", ""));
+
+    let ssh_user = parse_user("SSH_USER", &from_env_or_default("SSH_USER", "devpod"))?;
+
+    let allowed_ssh_cidrs = parse_cidrs(
+        "ALLOWED_SSH_CIDRS",
+        &from_env_or_default("ALLOWED_SSH_CIDRS", ""),
+    )?;
+
+    let auto_detect_source_ip = Some(from_env_or_default("AUTO_DETECT_SOURCE_IP", ""))
+        .map(|endpoint| endpoint.trim().to_string())
+        .filter(|endpoint| !endpoint.is_empty());
+
+    let extra_ingress_rules = parse_ingress_rules(
+        "EXTRA_INGRESS_RULES",
+        &from_env_or_default("EXTRA_INGRESS_RULES", ""),
+    )?;
 
     if init {
-        return Options {
+        return Ok(Options {
             template,
             instance_type,
             disk_size,
+            operation_timeout,
+            instance_ready_timeout,
+            ssh_ready_timeout,
+            cloud_init_timeout,
+            ssh_port,
+            ssh_connect_timeout,
+            ssh_connect_retries,
+            ssh_key_type,
+            ssh_public_key_path,
+            ssh_use_agent,
+            ssh_key_passphrase,
+            register_ssh_key,
+            extra_authorized_keys,
+            ssh_user,
+            allowed_ssh_cidrs,
+            auto_detect_source_ip,
+            extra_ingress_rules,
             ..Default::default()
-        };
+        });
     }
-    let mut machine_id = from_env_or_error("MACHINE_ID");
+    let mut machine_id = from_env_or_error("MACHINE_ID")?;
     machine_id = machine_id.to_string();
-    let machine_folder = from_env_or_error("MACHINE_FOLDER");
-    Options {
+    let machine_folder = from_env_or_error("MACHINE_FOLDER")?;
+    Ok(Options {
         template,
         instance_type,
         disk_size,
         machine_id,
         machine_folder,
-    }
+        operation_timeout,
+        instance_ready_timeout,
+        ssh_ready_timeout,
+        cloud_init_timeout,
+        ssh_port,
+        ssh_connect_timeout,
+        ssh_connect_retries,
+        ssh_key_type,
+        ssh_public_key_path,
+        ssh_use_agent,
+        ssh_key_passphrase,
+        register_ssh_key,
+        extra_authorized_keys,
+        ssh_user,
+        allowed_ssh_cidrs,
+        auto_detect_source_ip,
+        extra_ingress_rules,
+    })
 }
 
-fn from_env_or_error(name: &str) -> String {
+pub fn from_env_or_error(name: &str) -> Result<String, ProviderError> {
     let value = env::var(name);
     match value {
-        Ok(value) => value,
-        Err(err) => panic!("Error reading {} from environment: {}", name, err),
+        Ok(value) => Ok(value),
+        Err(err) => Err(ProviderError::Config(format!(
+            "Error reading {} from environment: {}",
+            name, err
+        ))),
+    }
+}
+
+// The passphrase comes either inline or from a file, which keeps it out of the option listing
+fn key_passphrase() -> Result<Option<String>, ProviderError> {
+    let passphrase = from_env_or_default("SSH_KEY_PASSPHRASE", "");
+    let passphrase_file = from_env_or_default("SSH_KEY_PASSPHRASE_FILE", "");
+    match (passphrase.is_empty(), passphrase_file.trim().is_empty()) {
+        (true, true) => Ok(None),
+        (false, true) => Ok(Some(passphrase)),
+        (true, false) => match fs::read_to_string(passphrase_file.trim()) {
+            Ok(content) => Ok(Some(content.trim_end_matches(['\r', '\n']).to_string())),
+            Err(err) => Err(ProviderError::Config(format!(
+                "Error reading SSH_KEY_PASSPHRASE_FILE {}: {}",
+                passphrase_file, err
+            ))),
+        },
+        (false, false) => Err(ProviderError::Config(
+            "Set either SSH_KEY_PASSPHRASE or SSH_KEY_PASSPHRASE_FILE, not both".to_string(),
+        )),
+    }
+}
+
+fn from_env_or_default(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ProviderError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| ProviderError::Config(format!("{} must be a number, got {:?}", name, value)))
+}
+
+fn parse_flag(name: &str, value: &str) -> Result<bool, ProviderError> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" | "" => Ok(false),
+        _ => Err(ProviderError::Config(format!(
+            "{} must be true or false, got {:?}",
+            name, value
+        ))),
+    }
+}
+
+// User names as POSIX allows them portably: letters, digits, dots, underscores and hyphens, not
+// starting with a hyphen. useradd additionally caps them at 32 characters.
+fn parse_user(name: &str, value: &str) -> Result<String, ProviderError> {
+    let user = value.trim();
+    let valid = !user.is_empty()
+        && user.len() <= 32
+        && !user.starts_with('-')
+        && !user.chars().all(|c| c.is_ascii_digit())
+        // Both would point AGENT_PATH outside of /home
+        && user != "."
+        && user != ".."
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        return Err(ProviderError::Config(format!(
+            "{} must be a valid user name (letters, digits, '.', '_' and '-', not starting with \
+             '-', not all digits, not . or .., at most 32 characters), got {:?}",
+            name, value
+        )));
+    }
+    Ok(user.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_users() {
+        assert_eq!(
+            parse_user("SSH_USER", "ci_user-1.x").unwrap(),
+            "ci_user-1.x"
+        );
+        assert_eq!(parse_user("SSH_USER", " devpod ").unwrap(), "devpod");
+        assert_eq!(parse_user("SSH_USER", &"a".repeat(32)).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn rejects_invalid_users() {
+        for value in [
+            "",
+            "-devpod",
+            "1000",
+            &"a".repeat(33),
+            ".",
+            "..",
+            "dévpod",
+            "dev pod",
+            "dev/pod",
+        ] {
+            assert!(parse_user("SSH_USER", value).is_err(), "{:?}", value);
+        }
     }
 }
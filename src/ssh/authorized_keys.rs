@@ -0,0 +1,201 @@
+use crate::error::ProviderError;
+use crate::ssh::helper::shell_quote;
+use crate::ssh::keys;
+
+// Additional keys to authorize, as given in EXTRA_AUTHORIZED_KEYS or on the command line
+#[derive(Debug, Default)]
+pub struct ExtraKeys {
+    // Public keys, given inline or read from files
+    pub public_keys: Vec<String>,
+    // Names of keys in the Exoscale SSH key registry, which only exposes their fingerprint
+    pub registered: Vec<String>,
+}
+
+// Sorts out the entries: inline public keys start with their algorithm, paths contain a slash,
+// anything else names a registered Exoscale SSH key
+pub fn resolve_extra_keys(entries: &[String]) -> Result<ExtraKeys, ProviderError> {
+    let mut extra_keys = ExtraKeys::default();
+    for entry in entries.iter().map(|entry| entry.trim()) {
+        if entry.is_empty() {
+            continue;
+        }
+        if is_inline_key(entry) {
+            let public_key = keys::parse_public_key(entry).map_err(ProviderError::Config)?;
+            extra_keys.public_keys.push(public_key);
+        } else if entry.contains('/') || entry.starts_with('~') {
+            extra_keys
+                .public_keys
+                .extend(keys::read_public_keys_file(entry)?);
+        } else {
+            extra_keys.registered.push(entry.to_string());
+        }
+    }
+    Ok(extra_keys)
+}
+
+// Splits EXTRA_AUTHORIZED_KEYS, entries are separated by commas or newlines
+pub fn split_extra_keys(value: &str) -> Vec<String> {
+    value
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_inline_key(entry: &str) -> bool {
+    ["ssh-", "ecdsa-", "sk-"]
+        .iter()
+        .any(|prefix| entry.starts_with(prefix))
+        && entry.contains(' ')
+}
+
+// Shell command adding a public key to the authorized_keys of the login user, unless it is
+// authorized already
+pub fn authorize_command(public_key: &str) -> String {
+    format!(
+        "mkdir -p ~/.ssh && chmod 700 ~/.ssh && touch ~/.ssh/authorized_keys && \
+         chmod 600 ~/.ssh/authorized_keys && \
+         {{ grep -qF {} ~/.ssh/authorized_keys || printf '%s\\n' {} >> ~/.ssh/authorized_keys; }}",
+        shell_quote(key_blob(public_key)),
+        shell_quote(public_key.trim())
+    )
+}
+
+// Shell command removing every line carrying the public key, whatever its options or comment
+pub fn revoke_command(public_key: &str) -> String {
+    format!(
+        "f=~/.ssh/authorized_keys; {{ grep -vF {} \"$f\" || true; }} > \"$f.tmp\" && \
+         chmod 600 \"$f.tmp\" && mv \"$f.tmp\" \"$f\"",
+        shell_quote(key_blob(public_key))
+    )
+}
+
+// The base64 part of a public key line, which identifies the key
+fn key_blob(public_key: &str) -> &str {
+    public_key
+        .split_whitespace()
+        .find(|field| field.starts_with("AAAA"))
+        .unwrap_or(public_key.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBkPwMMI2zEwKHOw2cnoGomXahhXWmHVEXd7hYX5567e";
+    const OTHER_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGlVCGiqhpZD6diHoqsIU8XlIwEMTH6IdN6xcPS1n/3C";
+
+    fn temp_home(name: &str) -> PathBuf {
+        let home = std::env::temp_dir().join(format!(
+            "devpod-authorized-keys-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        home
+    }
+
+    // Runs the command in a shell whose home is `home`, as it would on the VM
+    fn run(home: &PathBuf, command: &str) {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("HOME", home)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{}", command);
+    }
+
+    #[test]
+    fn splits_entries() {
+        assert_eq!(
+            split_extra_keys(" alice ,\n~/.ssh/bob.pub\n\n,carol"),
+            vec!["alice", "~/.ssh/bob.pub", "carol"]
+        );
+    }
+
+    #[test]
+    fn sorts_entries() {
+        let home = temp_home("sort");
+        let keys_file = home.join("team.pub");
+        fs::write(
+            &keys_file,
+            format!("# team\n{} bob\n\n{} carol\n", KEY, OTHER_KEY),
+        )
+        .unwrap();
+
+        let entries = vec![
+            format!("{} alice@laptop", KEY),
+            keys_file.display().to_string(),
+            "dave-registered".to_string(),
+            " ".to_string(),
+        ];
+        let extra_keys = resolve_extra_keys(&entries).unwrap();
+        assert_eq!(
+            extra_keys.public_keys,
+            vec![
+                format!("{} alice@laptop", KEY),
+                format!("{} bob", KEY),
+                format!("{} carol", OTHER_KEY),
+            ]
+        );
+        assert_eq!(extra_keys.registered, vec!["dave-registered"]);
+        fs::remove_dir_all(home).unwrap();
+    }
+
+    #[test]
+    fn recognizes_inline_keys() {
+        assert!(is_inline_key(KEY));
+        assert!(is_inline_key("ecdsa-sha2-nistp256 AAAA"));
+        assert!(is_inline_key("sk-ssh-ed25519@openssh.com AAAA"));
+        // A registered key may well be named after its algorithm
+        assert!(!is_inline_key("ssh-ed25519"));
+        assert!(!is_inline_key("alice"));
+        assert!(resolve_extra_keys(&["ssh-ed25519 garbage".to_string()]).is_err());
+    }
+
+    #[test]
+    fn authorizes_keys_with_quotes_in_the_comment() {
+        let home = temp_home("authorize");
+        let public_key = format!("{} it's me; $(touch pwned)", KEY);
+        run(&home, &authorize_command(&public_key));
+        // Authorizing twice keeps a single entry
+        run(&home, &authorize_command(&public_key));
+
+        let authorized_keys = fs::read_to_string(home.join(".ssh/authorized_keys")).unwrap();
+        assert_eq!(authorized_keys, format!("{}\n", public_key));
+        assert!(!home.join("pwned").exists());
+        fs::remove_dir_all(home).unwrap();
+    }
+
+    #[test]
+    fn revokes_by_key_blob() {
+        let home = temp_home("revoke");
+        let authorized_keys = home.join(".ssh/authorized_keys");
+        fs::write(
+            &authorized_keys,
+            format!(
+                "{} devpod\nno-pty,from=\"10.0.0.1\" {} old comment\n{} alice\n",
+                OTHER_KEY, KEY, KEY
+            ),
+        )
+        .unwrap();
+
+        run(
+            &home,
+            &revoke_command(&format!("{} some other comment", KEY)),
+        );
+        assert_eq!(
+            fs::read_to_string(&authorized_keys).unwrap(),
+            format!("{} devpod\n", OTHER_KEY)
+        );
+        fs::remove_dir_all(home).unwrap();
+    }
+}
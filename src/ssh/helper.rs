@@ -1,11 +1,18 @@
-use exoscale_rs::models::instance_type::Size;
-use ssh2::Session;
-use std::io::Read;
-use std::net::TcpStream;
-use std::time::Duration;
+use crate::ssh::known_hosts::{prefer_host_key, verify_host_key};
+use ssh2::{Channel, Session};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::task;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
 
 #[derive(Debug, Error)]
 pub enum SshError {
@@ -13,10 +20,14 @@ pub enum SshError {
     Session(String),
     #[error("IO error: {0}")]
     Io(String),
-    #[error("UTF-8 conversion error")]
-    Utf8,
-    #[error("Health check failed: Unable to connect to port 22 on {0}")]
+    #[error("Health check failed: {0}")]
     HealthCheckFailed(String),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Host key verification failed: {0}")]
+    HostKeyMismatch(String),
+    #[error("Remote command failed: {0}")]
+    RemoteCommand(String),
 }
 
 impl From<ssh2::Error> for SshError {
@@ -31,87 +42,495 @@ impl From<std::io::Error> for SshError {
     }
 }
 
-// Async function to check port 22 availability with retries
-async fn check_port(ip: &str, max_retries: u32, retry_delay: Duration) -> Result<(), SshError> {
-    for attempt in 0..max_retries {
-        match TcpStream::connect(format!("{}:22", ip)) {
-            Ok(_) => return Ok(()),
-            Err(_) if attempt < max_retries - 1 => {
-                sleep(retry_delay).await;
+// How to reach the SSH daemon of the workspace VM
+#[derive(Clone, Debug)]
+pub struct SshOptions {
+    pub port: u16,
+    pub connect_timeout: Duration,
+    pub connect_retries: u32,
+    pub known_hosts: PathBuf,
+    // Public host key the VM was provisioned with, if any
+    pub host_key: Option<String>,
+}
+
+const BACKOFF_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const BACKOFF_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Waits until the SSH port accepts connections, backing off exponentially between attempts
+async fn check_port(ip: &str, options: &SshOptions) -> Result<(), SshError> {
+    let attempts = options.connect_retries.max(1);
+    let mut delay = BACKOFF_INITIAL_DELAY;
+    let mut last_error = String::new();
+
+    for attempt in 1..=attempts {
+        let connect = tokio::net::TcpStream::connect((ip, options.port));
+        last_error = match timeout(options.connect_timeout, connect).await {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => format!("no answer within {}s", options.connect_timeout.as_secs()),
+        };
+        eprintln!(
+            "SSH port {} on {} not reachable (attempt {}/{}): {}",
+            options.port, ip, attempt, attempts, last_error
+        );
+        if attempt < attempts {
+            sleep(with_jitter(delay)).await;
+            delay = (delay * 2).min(BACKOFF_MAX_DELAY);
+        }
+    }
+
+    Err(SshError::HealthCheckFailed(format!(
+        "unable to connect to port {} on {} after {} attempts, last error: {}",
+        options.port, ip, attempts, last_error
+    )))
+}
+
+// Picks a delay between half and all of the given one, so that retries don't line up
+fn with_jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    let spread = u64::try_from(half.as_millis()).unwrap_or(u64::MAX);
+    let random = RandomState::new().build_hasher().finish();
+    half + Duration::from_millis(random % spread.saturating_add(1))
+}
+
+// Opens the TCP connection for a blocking session, bounded by the connect timeout
+fn open_stream(ip: &str, options: &SshOptions) -> Result<TcpStream, SshError> {
+    let mut last_error = None;
+    for address in (ip, options.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, options.connect_timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(match last_error {
+        Some(err) => SshError::from(err),
+        None => SshError::Io(format!("{} did not resolve to any address", ip)),
+    })
+}
+
+// What the provider logs in with
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    // The private key itself, in a format ssh2 can parse, with the passphrase it is encrypted with
+    PrivateKey {
+        key: String,
+        passphrase: Option<String>,
+    },
+    // The ssh-agent identity matching this public key
+    Agent(String),
+}
+
+const HANDSHAKE_TIMEOUT_MS: u32 = 30_000;
+
+// Opens a session and authenticates with the workspace key, blocking the calling thread
+fn connect(
+    user: &str,
+    ip: &str,
+    credentials: &Credentials,
+    options: &SshOptions,
+) -> Result<Session, SshError> {
+    let mut session = Session::new().map_err(SshError::from)?;
+
+    let tcp = open_stream(ip, options)?;
+    session.set_tcp_stream(tcp);
+    // Don't let a half-booted sshd stall the handshake forever
+    session.set_timeout(HANDSHAKE_TIMEOUT_MS);
+    prefer_host_key(&session, options)?;
+    session.handshake().map_err(SshError::from)?;
+    verify_host_key(&session, ip, options)?;
+
+    match credentials {
+        Credentials::PrivateKey { key, passphrase } => {
+            #[cfg(not(windows))]
+            {
+                session
+                    .userauth_pubkey_memory(user, None, key, passphrase.as_deref())
+                    .map_err(SshError::from)?;
+            }
+            // libssh2 on Windows can't read keys from memory, so the key goes through a
+            // temporary file that only lives for the authentication
+            #[cfg(windows)]
+            {
+                let key_file =
+                    std::env::temp_dir().join(format!("devpod-{}.key", uuid::Uuid::new_v4()));
+                let authenticated = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&key_file)
+                    .and_then(|mut file| file.write_all(key.as_bytes()))
+                    .map_err(SshError::from)
+                    .and_then(|()| {
+                        session
+                            .userauth_pubkey_file(user, None, &key_file, passphrase.as_deref())
+                            .map_err(SshError::from)
+                    });
+                let _ = std::fs::remove_file(&key_file);
+                authenticated?;
             }
-            Err(_) => return Err(SshError::HealthCheckFailed(ip.to_string())),
         }
+        Credentials::Agent(public_key) => authenticate_with_agent(&session, user, public_key)?,
     }
-    Ok(())
+    session.set_timeout(0);
+
+    Ok(session)
+}
+
+// Signs the login with the agent identity of the given public key, the private half never leaves
+// the agent
+fn authenticate_with_agent(
+    session: &Session,
+    user: &str,
+    public_key: &str,
+) -> Result<(), SshError> {
+    let key_blob = ssh_key::PublicKey::from_openssh(public_key.trim())
+        .and_then(|public_key| public_key.to_bytes())
+        .map_err(|err| SshError::Session(format!("Error parsing the public key: {}", err)))?;
+
+    let mut agent = session.agent().map_err(SshError::from)?;
+    agent.connect().map_err(|err| {
+        SshError::Session(format!(
+            "Error connecting to ssh-agent, is SSH_AUTH_SOCK set? {}",
+            err
+        ))
+    })?;
+    agent.list_identities().map_err(SshError::from)?;
+    let identity = agent
+        .identities()
+        .map_err(SshError::from)?
+        .into_iter()
+        .find(|identity| identity.blob() == key_blob.as_slice())
+        .ok_or_else(|| {
+            SshError::Session("ssh-agent does not hold the configured public key".to_string())
+        })?;
+    let result = agent.userauth(user, &identity).map_err(SshError::from);
+    let _ = agent.disconnect();
+    result
+}
+
+// Succeeds once the user can log in, without running anything
+pub async fn check_login(
+    user: String,
+    ip: String,
+    credentials: Credentials,
+    options: SshOptions,
+) -> Result<(), SshError> {
+    task::spawn_blocking(move || connect(&user, &ip, &credentials, &options).map(|_| ()))
+        .await
+        .map_err(|e| SshError::Io(e.to_string()))?
+}
+
+// Runs a command without a stdin and returns its exit status together with its stdout. Meant for
+// short checks done by the provider itself, everything is held in memory.
+pub async fn capture_command(
+    user: String,
+    ip: String,
+    credentials: Credentials,
+    command: String,
+    timeout: Duration,
+    options: SshOptions,
+) -> Result<(i32, String), SshError> {
+    task::spawn_blocking(move || {
+        let session = connect(&user, &ip, &credentials, &options)?;
+        session.set_timeout(timeout.as_millis().try_into().unwrap_or(u32::MAX));
+
+        let mut channel = session.channel_session().map_err(SshError::from)?;
+        channel.exec(&command).map_err(SshError::from)?;
+        channel.send_eof().map_err(SshError::from)?;
+
+        let mut output = Vec::new();
+        channel.read_to_end(&mut output).map_err(SshError::from)?;
+        channel.wait_close().map_err(SshError::from)?;
+
+        Ok((
+            exit_code(&channel)?,
+            String::from_utf8_lossy(&output).into_owned(),
+        ))
+    })
+    .await
+    .map_err(|e| SshError::Io(e.to_string()))?
 }
 
 pub async fn execute_command(
     user: String,
     ip: String,
-    private_key: String,
+    credentials: Credentials,
     command: String,
-) -> Result<String, SshError> {
-    // Health check for port 22 with retry
-    const MAX_RETRIES: u32 = 5;
-    const RETRY_DELAY: Duration = Duration::from_secs(5);
+    options: SshOptions,
+) -> Result<i32, SshError> {
+    check_port(&ip, &options).await?;
 
-    check_port(&ip, MAX_RETRIES, RETRY_DELAY).await?;
+    let interrupt = Arc::new(AtomicI32::new(0));
+    let watcher = tokio::spawn(watch_signals(interrupt.clone()));
 
-    task::spawn_blocking(move || {
-        let mut session = Session::new().map_err(SshError::from)?;
+    let result = task::spawn_blocking(move || {
+        let session = connect(&user, &ip, &credentials, &options)?;
 
-        let tcp = TcpStream::connect(format!("{}:22", ip)).map_err(SshError::from)?;
-        session.set_tcp_stream(tcp);
-        session.handshake().map_err(SshError::from)?;
+        let mut channel = session.channel_session().map_err(SshError::from)?;
+        channel
+            .exec(&wrap_command(&command))
+            .map_err(SshError::from)?;
 
-        #[cfg(any(target_os = "linux", target_os = "macos"))]
-        {
-            session
-                .userauth_pubkey_memory(&user, None, &private_key, None)
-                .map_err(SshError::from)?;
+        session.set_blocking(false);
+        let abandoned = relay(&session, &mut channel, &interrupt)?;
+        session.set_blocking(true);
+
+        if let Some(signal) = abandoned {
+            // The remote process ignored the forwarded signal, stop waiting for it
+            channel.close().map_err(SshError::from)?;
+            return Ok(128 + signal);
         }
-        #[cfg(target_os = "windows")]
-        {
-            session
-                .userauth_pubkey_file(&user, None, std::path::Path::new(&private_key), None)
-                .map_err(SshError::from)?;
+
+        channel.wait_close().map_err(SshError::from)?;
+
+        exit_code(&channel)
+    })
+    .await
+    .map_err(|e| SshError::Io(e.to_string()));
+
+    watcher.abort();
+    result?
+}
+
+// Runs the command under a small sh wrapper that turns a forwarded signal into SIGTERM for the
+// whole remote process group, so no child outlives an aborted command. The command runs in the
+// background and is waited on because sh defers traps until a foreground child returns; stdin is
+// passed through fd 3 since background jobs otherwise get /dev/null.
+fn wrap_command(command: &str) -> String {
+    const WRAPPER: &str = r#"exec 3<&0; trap "trap - HUP INT TERM; kill -s TERM 0" HUP INT TERM; "${SHELL:-sh}" -c "$1" <&3 3<&- & wait $!"#;
+    format!("exec sh -c '{}' devpod {}", WRAPPER, shell_quote(command))
+}
+
+// Single-quotes a value for a POSIX shell
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+// Exit code of the remote process, using the shell convention of 128+N for death by signal N
+fn exit_code(channel: &Channel) -> Result<i32, SshError> {
+    if let Some(signal) = channel.exit_signal().map_err(SshError::from)?.exit_signal {
+        return Ok(signal_number(&signal).map_or(255, |number| 128 + number));
+    }
+    channel.exit_status().map_err(SshError::from)
+}
+
+// Signal names as used by the SSH protocol (RFC 4254), without the SIG prefix
+fn signal_number(name: &str) -> Option<i32> {
+    match name {
+        "HUP" => Some(1),
+        "INT" => Some(2),
+        "QUIT" => Some(3),
+        "ILL" => Some(4),
+        "ABRT" => Some(6),
+        "FPE" => Some(8),
+        "KILL" => Some(9),
+        "USR1" => Some(10),
+        "SEGV" => Some(11),
+        "USR2" => Some(12),
+        "PIPE" => Some(13),
+        "ALRM" => Some(14),
+        "TERM" => Some(15),
+        _ => None,
+    }
+}
+
+fn signal_name(number: i32) -> Option<&'static str> {
+    match number {
+        1 => Some("HUP"),
+        2 => Some("INT"),
+        15 => Some("TERM"),
+        _ => None,
+    }
+}
+
+// Records Ctrl-C and SIGTERM received locally so the relay can forward them to the remote process
+async fn watch_signals(interrupt: Arc<AtomicI32>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (Ok(mut terminate), Ok(mut hangup)) = (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::hangup()),
+        ) else {
+            return;
+        };
+        loop {
+            let number = tokio::select! {
+                _ = tokio::signal::ctrl_c() => 2,
+                _ = terminate.recv() => 15,
+                _ = hangup.recv() => 1,
+            };
+            interrupt.store(number, Ordering::SeqCst);
         }
+    }
+    #[cfg(not(unix))]
+    {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            interrupt.store(2, Ordering::SeqCst);
+        }
+    }
+}
 
-        let mut channel = session.channel_session().map_err(SshError::from)?;
-        channel.exec(&command).map_err(SshError::from)?;
+// Sends a "signal" channel request (RFC 4254 6.9), retrying while the session would block
+fn send_signal(channel: &mut Channel, name: &str) -> Result<(), ssh2::Error> {
+    loop {
+        match channel.process_startup("signal", Some(name)) {
+            Err(err) if would_block(&err) => thread::sleep(RELAY_IDLE_DELAY),
+            result => return result,
+        }
+    }
+}
 
-        let mut output = String::new();
-        let mut buffer = [0; 1024];
+// Size of the chunks copied between the local stdio and the channel
+const RELAY_BUFFER_SIZE: usize = 32 * 1024;
+// Number of stdin chunks that may be queued before the reader thread blocks
+const STDIN_QUEUE_DEPTH: usize = 8;
+// Pause between polls while neither side has data to move
+const RELAY_IDLE_DELAY: Duration = Duration::from_millis(5);
+// How long the remote process gets to exit after a forwarded signal before the channel is closed
+const SIGNAL_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
+// libssh2 reports LIBSSH2_ERROR_EAGAIN when a non-blocking call has to be retried
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+fn would_block(err: &ssh2::Error) -> bool {
+    err.code() == ssh2::ErrorCode::Session(LIBSSH2_ERROR_EAGAIN)
+}
+
+// Codes of a write to a channel that was closed or already got its EOF. ssh2 turns them into
+// `ErrorKind::Other`, so they are looked up on the session.
+const LIBSSH2_ERROR_CHANNEL_CLOSED: i32 = -26;
+const LIBSSH2_ERROR_CHANNEL_EOF_SENT: i32 = -27;
+
+fn stdin_closed(session: &Session) -> bool {
+    matches!(
+        ssh2::Error::last_session_error(session).map(|err| err.code()),
+        Some(ssh2::ErrorCode::Session(
+            LIBSSH2_ERROR_CHANNEL_CLOSED | LIBSSH2_ERROR_CHANNEL_EOF_SENT
+        ))
+    )
+}
+
+// Reads the local stdin on a dedicated thread, since std offers no non-blocking stdin
+fn spawn_stdin_reader() -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::sync_channel(STDIN_QUEUE_DEPTH);
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buffer = vec![0; RELAY_BUFFER_SIZE];
         loop {
-            let size = channel.read(&mut buffer).map_err(SshError::from)?;
+            match stdin.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => {
+                    if sender.send(buffer[..size].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
 
-            if size == 0 {
-                break;
+// Reads whatever the stream has available and copies it to `out`, returning the number of bytes moved
+fn drain(
+    stream: &mut impl Read,
+    out: &mut impl Write,
+    buffer: &mut [u8],
+) -> Result<usize, SshError> {
+    match stream.read(buffer) {
+        Ok(0) => Ok(0),
+        Ok(size) => {
+            out.write_all(&buffer[..size])?;
+            out.flush()?;
+            Ok(size)
+        }
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
+        Err(err) => Err(SshError::from(err)),
+    }
+}
+
+// Full-duplex copy between the local stdio and a non-blocking channel until the remote side closes.
+// Returns the forwarded signal when the remote process did not exit within the grace period.
+fn relay(
+    session: &Session,
+    channel: &mut Channel,
+    interrupt: &AtomicI32,
+) -> Result<Option<i32>, SshError> {
+    let stdin = spawn_stdin_reader();
+    let mut stdout = io::stdout().lock();
+    let mut stderr = io::stderr().lock();
+    let mut buffer = vec![0; RELAY_BUFFER_SIZE];
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut written = 0;
+    let mut stdin_open = true;
+    let mut forwarded: Option<(i32, Instant)> = None;
+
+    loop {
+        let signal = interrupt.swap(0, Ordering::SeqCst);
+        if signal != 0 {
+            if let Some(name) = signal_name(signal) {
+                if let Err(err) = send_signal(channel, name) {
+                    eprintln!(
+                        "Unable to forward SIG{} to the remote command: {}",
+                        name, err
+                    );
+                }
             }
+            forwarded.get_or_insert((signal, Instant::now()));
+        }
+        if let Some((signal, since)) = forwarded {
+            if since.elapsed() >= SIGNAL_GRACE_PERIOD {
+                return Ok(Some(signal));
+            }
+        }
 
-            output.push_str(std::str::from_utf8(&buffer[..size]).map_err(|_| SshError::Utf8)?);
+        let mut progressed = drain(channel, &mut stdout, &mut buffer)? > 0;
+        progressed |= drain(&mut channel.stderr(), &mut stderr, &mut buffer)? > 0;
+
+        if written == pending.len() && stdin_open {
+            match stdin.try_recv() {
+                Ok(chunk) => {
+                    pending = chunk;
+                    written = 0;
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => match channel.send_eof() {
+                    Ok(()) => {
+                        stdin_open = false;
+                        progressed = true;
+                    }
+                    Err(err) if would_block(&err) => {}
+                    Err(err) => return Err(SshError::from(err)),
+                },
+            }
         }
 
-        Ok(output)
-    })
-    .await
-    .map_err(|e| SshError::Io(e.to_string()))?
-}
+        if written < pending.len() {
+            match channel.write(&pending[written..]) {
+                Ok(size) => {
+                    written += size;
+                    progressed |= size > 0;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                // The remote side no longer takes input, keep draining its output
+                Err(_) if stdin_closed(session) => {
+                    pending.clear();
+                    written = 0;
+                    stdin_open = false;
+                }
+                Err(err) => return Err(SshError::from(err)),
+            }
+        }
 
-pub fn map_str_to_size(size_str: &str) -> Option<Size> {
-    match size_str {
-        "large" => Some(Size::Large),
-        "huge" => Some(Size::Huge),
-        "jumbo" => Some(Size::Jumbo),
-        "medium" => Some(Size::Medium),
-        "mega" => Some(Size::Mega),
-        "small" => Some(Size::Small),
-        "extra-large" => Some(Size::ExtraLarge),
-        "titan" => Some(Size::Titan),
-        "micro" => Some(Size::Micro),
-        "colossus" => Some(Size::Colossus),
-        "tiny" => Some(Size::Tiny),
-        _ => None,
+        if !progressed {
+            if channel.eof() {
+                break;
+            }
+            thread::sleep(RELAY_IDLE_DELAY);
+        }
     }
+
+    Ok(None)
 }
@@ -1,26 +1,183 @@
-use std::fs;
-use std::io::ErrorKind;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use openssh_keys::PublicKey;
-use openssl::pkey::PKey;
-use openssl::rsa::Rsa;
+use ssh_key::private::RsaKeypair;
+use ssh_key::rand_core::OsRng;
+use ssh_key::{Algorithm, EcdsaCurve, HashAlg, LineEnding, PrivateKey, PublicKey};
 
-static DEV_POD_SSH_PRIVATE_KEY_FILE: &str = "id_devpod_rsa";
-static DEV_POD_SSH_PUBLIC_KEY_FILE: &str = "id_devpod_rsa.pub";
+use crate::error::ProviderError;
 
-fn make_ssh_key_pair() -> (String, String) {
-    let rsa = Rsa::generate(2048).unwrap();
-    let private_key = PKey::from_rsa(rsa.clone()).unwrap();
-    let public_key = PublicKey::from_rsa(rsa.e().to_vec(), rsa.n().to_vec()).to_string();
-    let private_key_raw =
-        String::from_utf8(private_key.clone().private_key_to_pem_pkcs8().unwrap());
-    (public_key, private_key_raw.unwrap())
+static DEV_POD_SSH_HOST_KEY_FILE: &str = "ssh_host_ed25519_key";
+static DEV_POD_SSH_HOST_PUBLIC_KEY_FILE: &str = "ssh_host_ed25519_key.pub";
+static DEV_POD_SSH_KEY_COMMENT: &str = "devpod";
+static DEV_POD_SSH_KEY_LOCK_FILE: &str = "keys.lock";
+
+// Algorithm of the workspace key pair, only used when a new pair is generated
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    EcdsaP256,
+    Rsa4096,
+}
+
+impl KeyType {
+    const ALL: [KeyType; 3] = [KeyType::Ed25519, KeyType::EcdsaP256, KeyType::Rsa4096];
+
+    fn private_key_file(self) -> &'static str {
+        match self {
+            KeyType::Ed25519 => "id_devpod_ed25519",
+            KeyType::EcdsaP256 => "id_devpod_ecdsa",
+            // Also the name of the RSA-2048 pairs made by earlier releases
+            KeyType::Rsa4096 => "id_devpod_rsa",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            KeyType::Ed25519 => "ed25519",
+            KeyType::EcdsaP256 => "ecdsa-p256",
+            KeyType::Rsa4096 => "rsa-4096",
+        }
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        KeyType::ALL
+            .into_iter()
+            .find(|key_type| key_type.name() == value.trim().to_lowercase())
+            .ok_or_else(|| {
+                format!(
+                    "unknown key type {:?}, expected one of {}",
+                    value,
+                    KeyType::ALL.map(KeyType::name).join(", ")
+                )
+            })
+    }
+}
+
+fn make_ssh_key_pair(
+    key_type: KeyType,
+    passphrase: Option<&str>,
+) -> Result<(String, String), ProviderError> {
+    let mut private_key = match key_type {
+        KeyType::Ed25519 => PrivateKey::random(&mut OsRng, Algorithm::Ed25519),
+        KeyType::EcdsaP256 => PrivateKey::random(
+            &mut OsRng,
+            Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256,
+            },
+        ),
+        KeyType::Rsa4096 => RsaKeypair::random(&mut OsRng, 4096).map(PrivateKey::from),
+    }
+    .map_err(key_store_error("generating the key pair"))?;
+    private_key.set_comment(DEV_POD_SSH_KEY_COMMENT);
+
+    let public_key = private_key
+        .public_key()
+        .to_openssh()
+        .map_err(key_store_error("encoding the public key"))?;
+    if let Some(passphrase) = passphrase {
+        private_key = private_key
+            .encrypt(&mut OsRng, passphrase)
+            .map_err(key_store_error("encrypting the private key"))?;
+    }
+    let private_key_raw = private_key
+        .to_openssh(LineEnding::LF)
+        .map_err(key_store_error("encoding the private key"))?
+        .to_string();
+    Ok((public_key, private_key_raw))
+}
+
+// Picks the pair already in the machine folder, whatever its type, so machines keep the key they
+// were created with. A new pair of the requested type is generated when there is none.
+fn ensure_key_pair(
+    path: &Path,
+    key_type: KeyType,
+    passphrase: Option<&str>,
+) -> Result<(PathBuf, PathBuf), ProviderError> {
+    let preferred = std::iter::once(key_type).chain(KeyType::ALL);
+    for candidate in preferred {
+        let private_key_file = path.join(candidate.private_key_file());
+        if private_key_file.exists() {
+            if let Some(passphrase) = passphrase {
+                encrypt_existing_key(&private_key_file, passphrase)?;
+            }
+            let public_key_file = private_key_file.with_extension("pub");
+            return Ok((private_key_file, public_key_file));
+        }
+    }
+
+    let private_key_file = path.join(key_type.private_key_file());
+    let public_key_file = private_key_file.with_extension("pub");
+    let (public_key, private_key) = make_ssh_key_pair(key_type, passphrase)?;
+    // The private key goes last, once it exists the pair is complete
+    write_key(&public_key_file, &public_key, 0o644)?;
+    write_key(&private_key_file, &private_key, 0o600)?;
+    Ok((private_key_file, public_key_file))
+}
+
+// The public half of the pair already in the machine folder, without taking the lock or
+// generating a pair when there is none
+pub fn find_public_key(dir: &str, key_type: KeyType) -> Result<Option<String>, ProviderError> {
+    let preferred = std::iter::once(key_type).chain(KeyType::ALL);
+    for candidate in preferred {
+        let public_key_file = Path::new(dir)
+            .join(candidate.private_key_file())
+            .with_extension("pub");
+        match fs::read_to_string(&public_key_file) {
+            Ok(public_key) => return Ok(Some(public_key.trim().to_string())),
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(key_store_error(format!(
+                    "reading {}",
+                    public_key_file.display()
+                ))(err))
+            }
+        }
+    }
+    Ok(None)
+}
+
+// A passphrase set after the key was made protects the existing key from then on, instead of
+// leaving it in plaintext. Keys in the legacy PEM format have to be replaced with rotate-key.
+fn encrypt_existing_key(private_key_file: &Path, passphrase: &str) -> Result<(), ProviderError> {
+    let private_key = read_key(private_key_file)?;
+    let openssh_key = match PrivateKey::from_openssh(&private_key) {
+        Ok(openssh_key) => openssh_key,
+        Err(_) if private_key.contains("ENCRYPTED") => return Ok(()),
+        Err(_) => {
+            return Err(ProviderError::Config(format!(
+                "SSH_KEY_PASSPHRASE is set, but {} is an unencrypted key in a format that can't \
+                 be encrypted in place, run rotate-key to replace it with an encrypted key",
+                private_key_file.display()
+            )))
+        }
+    };
+    if openssh_key.is_encrypted() {
+        return Ok(());
+    }
+    let encrypted = openssh_key
+        .encrypt(&mut OsRng, passphrase)
+        .map_err(key_store_error("encrypting the private key"))?
+        .to_openssh(LineEnding::LF)
+        .map_err(key_store_error("encoding the private key"))?
+        .to_string();
+    write_key(private_key_file, &encrypted, 0o600)?;
+    eprintln!(
+        "Encrypted {} with the SSH key passphrase",
+        private_key_file.display()
+    );
+    Ok(())
 }
 
 /*pub fn get_private_key_filename(dir: String) -> String {
@@ -29,69 +186,341 @@ fn make_ssh_key_pair() -> (String, String) {
     private_key_file.to_str().unwrap().to_string()
 }*/
 
-pub fn get_private_key_raw_base(dir: String) -> String {
-    let key_lock = Arc::new(Mutex::new(()));
+pub struct KeyPair {
+    pub private_key: String,
+    pub public_key: String,
+}
+
+// Reads both halves of the workspace key pair while holding the key store lock, generating the
+// pair first if the machine has none. A new private key is encrypted when a passphrase is given.
+pub fn get_key_pair(
+    dir: &str,
+    key_type: KeyType,
+    passphrase: Option<&str>,
+) -> Result<KeyPair, ProviderError> {
+    prepare_key_store(dir)?;
+    let _lock = lock_key_store(dir)?;
+
+    let (private_key_file, public_key_file) =
+        ensure_key_pair(Path::new(dir), key_type, passphrase)?;
+    Ok(KeyPair {
+        private_key: read_key(&private_key_file)?,
+        public_key: read_key(&public_key_file)?,
+    })
+}
+
+pub fn get_private_key_raw_base(
+    dir: String,
+    key_type: KeyType,
+    passphrase: Option<&str>,
+) -> Result<String, ProviderError> {
+    get_key_pair(&dir, key_type, passphrase).map(|key_pair| key_pair.private_key)
+}
+
+pub fn get_public_key_base(
+    dir: String,
+    key_type: KeyType,
+    passphrase: Option<&str>,
+) -> Result<String, ProviderError> {
+    get_key_pair(&dir, key_type, passphrase).map(|key_pair| key_pair.public_key)
+}
+
+// Generates the pair that replaces the workspace key pair during a rotation. It waits next to the
+// current pair, under names `get_key_pair` ignores, until it is promoted or discarded.
+pub fn stage_key_pair(
+    dir: &str,
+    key_type: KeyType,
+    passphrase: Option<&str>,
+) -> Result<KeyPair, ProviderError> {
+    prepare_key_store(dir)?;
+    let _lock = lock_key_store(dir)?;
+
+    let (private_key_file, public_key_file) = staged_key_files(Path::new(dir), key_type);
+    let (public_key, private_key) = make_ssh_key_pair(key_type, passphrase)?;
+    write_key(&public_key_file, &public_key, 0o644)?;
+    write_key(&private_key_file, &private_key, 0o600)?;
+    Ok(KeyPair {
+        private_key,
+        public_key,
+    })
+}
+
+// Makes the staged pair the workspace key pair and drops the pairs of every other type
+pub fn promote_staged_key_pair(dir: &str, key_type: KeyType) -> Result<(), ProviderError> {
+    let _lock = lock_key_store(dir)?;
+
+    let path = Path::new(dir);
+    let private_key_file = path.join(key_type.private_key_file());
+    let public_key_file = private_key_file.with_extension("pub");
+    let (staged_private_key_file, staged_public_key_file) = staged_key_files(path, key_type);
+    for (staged, file) in [
+        (&staged_public_key_file, &public_key_file),
+        (&staged_private_key_file, &private_key_file),
+    ] {
+        fs::rename(staged, file)
+            .map_err(key_store_error(format!("replacing {}", file.display())))?;
+    }
+
+    for other in KeyType::ALL.into_iter().filter(|other| *other != key_type) {
+        let other_private_key_file = path.join(other.private_key_file());
+        remove_key(&other_private_key_file)?;
+        remove_key(&other_private_key_file.with_extension("pub"))?;
+    }
+    Ok(())
+}
+
+pub fn discard_staged_key_pair(dir: &str, key_type: KeyType) -> Result<(), ProviderError> {
+    let _lock = lock_key_store(dir)?;
 
-    let _guard = key_lock.lock().unwrap();
-    match fs::create_dir_all(dir.clone()) {
-        Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
-        _ => {}
+    let (private_key_file, public_key_file) = staged_key_files(Path::new(dir), key_type);
+    remove_key(&private_key_file)?;
+    remove_key(&public_key_file)
+}
+
+fn staged_key_files(path: &Path, key_type: KeyType) -> (PathBuf, PathBuf) {
+    let private_key_file = format!("{}.next", key_type.private_key_file());
+    let public_key_file = format!("{}.pub", private_key_file);
+    (path.join(private_key_file), path.join(public_key_file))
+}
+
+// Fails early when an encrypted private key cannot be unlocked, ssh2 would only report a failed
+// login. Keys in the PEM format are left to ssh2.
+pub fn check_passphrase(private_key: &str, passphrase: Option<&str>) -> Result<(), ProviderError> {
+    let openssh_key = PrivateKey::from_openssh(private_key).ok();
+    let encrypted = match &openssh_key {
+        Some(openssh_key) => openssh_key.is_encrypted(),
+        None => private_key.contains("ENCRYPTED"),
+    };
+    if !encrypted {
+        return Ok(());
     }
 
+    let passphrase = passphrase.ok_or_else(|| {
+        ProviderError::Config(
+            "The SSH private key is encrypted, set SSH_KEY_PASSPHRASE or SSH_KEY_PASSPHRASE_FILE"
+                .to_string(),
+        )
+    })?;
+    match openssh_key {
+        Some(openssh_key) if openssh_key.decrypt(passphrase).is_err() => {
+            Err(ProviderError::Config(
+                "The SSH key passphrase does not unlock the private key".to_string(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn prepare_key_store(dir: &str) -> Result<(), ProviderError> {
+    fs::create_dir_all(dir).map_err(key_store_error(format!("preparing {}", dir)))?;
+
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     {
-        fs::set_permissions(dir.clone(), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o755))
+            .map_err(key_store_error(format!("preparing {}", dir)))?;
     }
+    Ok(())
+}
 
-    let path = Path::new(dir.as_str());
-    let private_key_file = path.join(DEV_POD_SSH_PRIVATE_KEY_FILE);
-    let public_key_file = path.join(DEV_POD_SSH_PUBLIC_KEY_FILE);
-    if !private_key_file.exists() {
-        let (public_key, private_key) = make_ssh_key_pair();
-        fs::write(private_key_file.clone(), private_key.as_str()).unwrap();
-        fs::write(public_key_file.clone(), public_key.as_str()).unwrap();
-        #[cfg(any(target_os = "linux", target_os = "macos"))]
-        {
-            fs::set_permissions(public_key_file.clone(), fs::Permissions::from_mode(0o644))
-                .unwrap();
-            fs::set_permissions(private_key_file.clone(), fs::Permissions::from_mode(0o600))
-                .unwrap();
-        }
+// Advisory lock shared by every provider process working on the machine, DevPod runs some of
+// them concurrently. Released when the returned file is dropped.
+fn lock_key_store(dir: &str) -> Result<File, ProviderError> {
+    let lock_file = Path::new(dir).join(DEV_POD_SSH_KEY_LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_file)
+        .map_err(key_store_error(format!("opening {}", lock_file.display())))?;
+    file.lock()
+        .map_err(key_store_error(format!("locking {}", lock_file.display())))?;
+    Ok(file)
+}
+
+// Reads a public key the user brought along, `~/` stands for the home directory
+pub fn read_user_public_key(public_key_path: &str) -> Result<String, ProviderError> {
+    let public_key_file = expand_home(public_key_path);
+    let public_key = read_key(&public_key_file)?;
+    PublicKey::from_openssh(public_key.trim()).map_err(key_store_error(format!(
+        "parsing {}",
+        public_key_file.display()
+    )))?;
+    Ok(public_key.trim().to_string())
+}
+
+// Reads a file in the authorized_keys format, one public key per line
+pub fn read_public_keys_file(path: &str) -> Result<Vec<String>, ProviderError> {
+    let keys_file = expand_home(path);
+    read_key(&keys_file)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            parse_public_key(line).map_err(|err| {
+                ProviderError::Config(format!("Error parsing {}: {}", keys_file.display(), err))
+            })
+        })
+        .collect()
+}
+
+// Checks a single public key line, options in front of the key are not supported
+pub fn parse_public_key(line: &str) -> Result<String, String> {
+    PublicKey::from_openssh(line.trim())
+        .map(|_| line.trim().to_string())
+        .map_err(|err| format!("invalid public key {:?}: {}", line.trim(), err))
+}
+
+// Reads the private half of a user key, which by convention sits next to the public key without
+// the `.pub` extension
+pub fn read_user_private_key(public_key_path: &str) -> Result<String, ProviderError> {
+    let public_key_file = expand_home(public_key_path);
+    match public_key_file.extension() {
+        Some(extension) if extension == "pub" => read_key(&public_key_file.with_extension("")),
+        _ => Err(ProviderError::KeyStore(format!(
+            "Error locating the private key of {}: expected a path ending in .pub",
+            public_key_file.display()
+        ))),
     }
+}
 
-    fs::read_to_string(private_key_file).unwrap()
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(relative), Some(home)) => Path::new(&home).join(relative),
+        _ => PathBuf::from(path),
+    }
 }
 
-pub fn get_public_key_base(dir: String) -> String {
-    let key_lock = Arc::new(Mutex::new(()));
+// Generates the host key a new VM gets provisioned with, so that its identity is known before
+// it ever boots. Replaces the key of a previous VM and returns the private and the public key.
+// Only the public key is stored, whoever holds the private key could impersonate the VM.
+pub fn make_host_key_pair(dir: &str) -> Result<(String, String), ProviderError> {
+    prepare_key_store(dir)?;
+    let _lock = lock_key_store(dir)?;
+
+    let host_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+        .map_err(key_store_error("generating the host key"))?;
+    let private_key = host_key
+        .to_openssh(LineEnding::LF)
+        .map_err(key_store_error("encoding the host key"))?
+        .to_string();
+    let public_key = host_key
+        .public_key()
+        .to_openssh()
+        .map_err(key_store_error("encoding the host key"))?;
 
-    let _guard = key_lock.lock().unwrap();
+    let path = Path::new(dir);
+    // Machines created by earlier versions kept the private key as well
+    remove_key(&path.join(DEV_POD_SSH_HOST_KEY_FILE))?;
+    write_key(
+        &path.join(DEV_POD_SSH_HOST_PUBLIC_KEY_FILE),
+        &public_key,
+        0o644,
+    )?;
+    Ok((private_key, public_key))
+}
 
-    match fs::create_dir_all(dir.clone()) {
-        Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
-        _ => {}
+// The public host key the VM was provisioned with, machines created before host keys were
+// generated locally have none
+pub fn get_host_public_key(dir: &str) -> Result<Option<String>, ProviderError> {
+    let public_key_file = Path::new(dir).join(DEV_POD_SSH_HOST_PUBLIC_KEY_FILE);
+    match fs::read_to_string(&public_key_file) {
+        Ok(public_key) => Ok(Some(public_key.trim().to_string())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(key_store_error(format!(
+            "reading {}",
+            public_key_file.display()
+        ))(err)),
     }
+}
+
+pub fn remove_host_key_pair(dir: &str) -> Result<(), ProviderError> {
+    for file in [DEV_POD_SSH_HOST_KEY_FILE, DEV_POD_SSH_HOST_PUBLIC_KEY_FILE] {
+        remove_key(&Path::new(dir).join(file))?;
+    }
+    Ok(())
+}
+
+// SHA256 fingerprint in the notation used by ssh-keygen -l
+pub fn public_key_fingerprint(public_key: &str) -> Option<String> {
+    PublicKey::from_openssh(public_key.trim())
+        .ok()
+        .map(|key| key.fingerprint(HashAlg::Sha256).to_string())
+}
 
+// Writes to a temporary file with the final permissions and renames it into place, readers never
+// see a partial key
+fn write_key(file: &Path, content: &str, mode: u32) -> Result<(), ProviderError> {
+    let mut temporary = file.as_os_str().to_owned();
+    temporary.push(".tmp");
+    replace_file(Path::new(&temporary), file, content, mode)
+        .map_err(key_store_error(format!("writing {}", file.display())))
+}
+
+#[cfg_attr(target_os = "windows", allow(unused_variables))]
+fn replace_file(temporary: &Path, file: &Path, content: &str, mode: u32) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).truncate(true).write(true);
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        options.mode(mode);
+    }
+    let mut key_file = options.open(temporary)?;
+    // The mode above only applies when the file is newly created
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     {
-        fs::set_permissions(dir.clone(), fs::Permissions::from_mode(0o755)).unwrap();
+        key_file.set_permissions(fs::Permissions::from_mode(mode))?;
     }
+    key_file.write_all(content.as_bytes())?;
+    key_file.sync_all()?;
+    fs::rename(temporary, file)
+}
 
-    let path = Path::new(dir.as_str());
-    let private_key_file = path.join(DEV_POD_SSH_PRIVATE_KEY_FILE);
-    let public_key_file = path.join(DEV_POD_SSH_PUBLIC_KEY_FILE);
-    if !private_key_file.exists() {
-        let (public_key, private_key) = make_ssh_key_pair();
-        fs::write(private_key_file.clone(), private_key.as_str()).unwrap();
-        fs::write(public_key_file.clone(), public_key.as_str()).unwrap();
-
-        #[cfg(any(target_os = "linux", target_os = "macos"))]
-        {
-            fs::set_permissions(private_key_file, fs::Permissions::from_mode(0o600)).unwrap();
-            fs::set_permissions(public_key_file.clone(), fs::Permissions::from_mode(0o644))
-                .unwrap();
+fn remove_key(file: &Path) -> Result<(), ProviderError> {
+    match fs::remove_file(file) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(key_store_error(format!("removing {}", file.display()))(err))
         }
+        _ => Ok(()),
     }
+}
+
+fn read_key(file: &Path) -> Result<String, ProviderError> {
+    fs::read_to_string(file).map_err(key_store_error(format!("reading {}", file.display())))
+}
+
+fn key_store_error<E: std::fmt::Display>(
+    context: impl Into<String>,
+) -> impl Fn(E) -> ProviderError {
+    let context = context.into();
+    move |err| ProviderError::KeyStore(format!("Error {}: {}", context, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypts_an_existing_key_once_a_passphrase_is_set() {
+        let dir = std::env::temp_dir().join(format!("devpod-keys-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap();
 
-    fs::read_to_string(public_key_file).unwrap()
+        let plain = get_key_pair(dir, KeyType::Ed25519, None).unwrap();
+        assert!(!PrivateKey::from_openssh(&plain.private_key)
+            .unwrap()
+            .is_encrypted());
+
+        let encrypted = get_key_pair(dir, KeyType::Ed25519, Some("secret")).unwrap();
+        assert_eq!(encrypted.public_key, plain.public_key);
+        assert!(PrivateKey::from_openssh(&encrypted.private_key)
+            .unwrap()
+            .is_encrypted());
+        check_passphrase(&encrypted.private_key, Some("secret")).unwrap();
+        assert!(check_passphrase(&encrypted.private_key, Some("wrong")).is_err());
+
+        // Already encrypted keys stay as they are
+        let again = get_key_pair(dir, KeyType::Ed25519, Some("secret")).unwrap();
+        assert_eq!(again.private_key, encrypted.private_key);
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,149 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose, Engine as _};
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, MethodType, Session};
+use ssh_key::PublicKey;
+
+use crate::ssh::helper::{SshError, SshOptions};
+
+static KNOWN_HOSTS_FILE: &str = "known_hosts";
+
+pub fn known_hosts_path(machine_folder: &str) -> PathBuf {
+    Path::new(machine_folder).join(KNOWN_HOSTS_FILE)
+}
+
+// Asks the server for the kind of key it was provisioned with, it may hold others as well
+pub fn prefer_host_key(session: &Session, options: &SshOptions) -> Result<(), SshError> {
+    if let Some(algorithm) = options
+        .host_key
+        .as_deref()
+        .and_then(|host_key| host_key.split_whitespace().next())
+    {
+        session
+            .method_pref(MethodType::HostKey, algorithm)
+            .map_err(SshError::from)?;
+    }
+    Ok(())
+}
+
+// Checks the host key against the one the VM was provisioned with. Machines without one pin the
+// key on the first connection and insist on the same key afterwards.
+pub fn verify_host_key(session: &Session, ip: &str, options: &SshOptions) -> Result<(), SshError> {
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| SshError::Session(format!("{} did not present a host key", ip)))?;
+
+    match &options.host_key {
+        Some(expected) => verify_provisioned_key(session, key, ip, options.port, expected),
+        None => verify_known_host(
+            session,
+            key,
+            key_type,
+            ip,
+            options.port,
+            &options.known_hosts,
+        ),
+    }
+}
+
+fn verify_provisioned_key(
+    session: &Session,
+    key: &[u8],
+    ip: &str,
+    port: u16,
+    expected: &str,
+) -> Result<(), SshError> {
+    let expected = PublicKey::from_openssh(expected)
+        .and_then(|public_key| public_key.to_bytes())
+        .map_err(|err| {
+            SshError::Session(format!("Error parsing the provisioned host key: {}", err))
+        })?;
+    if key == expected.as_slice() {
+        return Ok(());
+    }
+    Err(SshError::HostKeyMismatch(format!(
+        "{} presents host key {}, which is not the one the VM was provisioned with",
+        host_entry(ip, port),
+        host_key_fingerprint(session)
+    )))
+}
+
+fn verify_known_host(
+    session: &Session,
+    key: &[u8],
+    key_type: HostKeyType,
+    ip: &str,
+    port: u16,
+    known_hosts_file: &Path,
+) -> Result<(), SshError> {
+    let mut known_hosts = session.known_hosts().map_err(SshError::from)?;
+    if known_hosts_file.exists() {
+        known_hosts
+            .read_file(known_hosts_file, KnownHostFileKind::OpenSSH)
+            .map_err(|err| {
+                SshError::Io(format!(
+                    "Error reading {}: {}",
+                    known_hosts_file.display(),
+                    err
+                ))
+            })?;
+    }
+
+    match known_hosts.check_port(ip, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(SshError::HostKeyMismatch(format!(
+            "{} presents host key {}, which is not the one pinned in {}. If the VM was rebuilt \
+             outside of DevPod, remove that file to trust the new key.",
+            host_entry(ip, port),
+            host_key_fingerprint(session),
+            known_hosts_file.display()
+        ))),
+        CheckResult::NotFound => {
+            known_hosts
+                .add(&host_entry(ip, port), key, "devpod", key_type.into())
+                .map_err(SshError::from)?;
+            // Replace the file in one go, a concurrent reader never sees half of it
+            let temporary = known_hosts_file.with_extension("tmp");
+            known_hosts
+                .write_file(&temporary, KnownHostFileKind::OpenSSH)
+                .map_err(SshError::from)?;
+            fs::rename(&temporary, known_hosts_file).map_err(SshError::from)?;
+            eprintln!(
+                "Pinned host key {} for {}",
+                host_key_fingerprint(session),
+                host_entry(ip, port)
+            );
+            Ok(())
+        }
+        CheckResult::Failure => Err(SshError::Session(format!(
+            "Error checking the host key of {} against {}",
+            host_entry(ip, port),
+            known_hosts_file.display()
+        ))),
+    }
+}
+
+// Drops the pinned key, the next connection trusts whatever the new VM presents
+pub fn forget_host_keys(machine_folder: &str) -> Result<(), SshError> {
+    match fs::remove_file(known_hosts_path(machine_folder)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(SshError::from(err)),
+        _ => Ok(()),
+    }
+}
+
+// OpenSSH only spells out the port when it is not the default one
+fn host_entry(ip: &str, port: u16) -> String {
+    match port {
+        22 => ip.to_string(),
+        _ => format!("[{}]:{}", ip, port),
+    }
+}
+
+fn host_key_fingerprint(session: &Session) -> String {
+    match session.host_key_hash(HashType::Sha256) {
+        Some(hash) => format!("SHA256:{}", general_purpose::STANDARD_NO_PAD.encode(hash)),
+        None => "(unknown)".to_string(),
+    }
+}
@@ -1,2 +1,4 @@
+pub mod authorized_keys;
 pub mod helper;
 pub mod keys;
+pub mod known_hosts;